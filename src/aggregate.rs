@@ -1,106 +1,545 @@
 
+use crate::expression::{ Predicate };
 use crate::log_record::{ Accessor, LogRecord, LogValue };
 use crate::operation::{ Operation, OpType, build_operation };
+use crate::time_bucket::{ self, GapFill, TimeBucket };
 
-use std::collections::{ HashMap };
+use std::cmp::{ Ordering };
+use std::collections::{ BTreeSet, HashMap, HashSet };
+use serde_json::{ Map, Value };
 use std::io::{ BufRead };
 
 
+/// Maximum number of rows inserted by gap filling, to avoid exhausting memory.
+pub const MAX_FILLED_ROWS: usize = 100_000;
+
+/// Key of a row. One value for each index, or a prefix of them for subtotals.
+pub type RowKey = Vec<String>;
+
+/// Kind of rows in the output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RowKind {
+    /// Row for a combination of index values.
+    Group,
+    /// Subtotal of the groups sharing the key prefix.
+    Subtotal,
+    /// Total of all records.
+    Total,
+    /// Rows beyond the limit merged into one.
+    Others,
+    /// Row for records without some of index values.
+    Undefined,
+}
+
+impl RowKind {
+    /// Name of the kind in machine-readable outputs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RowKind::Group => "group",
+            RowKind::Subtotal => "subtotal",
+            RowKind::Total => "total",
+            RowKind::Others => "others",
+            RowKind::Undefined => "undefined",
+        }
+    }
+}
+
 pub struct Table {
     pub definition :TableDef,
-    pub rows :HashMap<String, TableRow>,
+    pub rows :HashMap<RowKey, TableRow>,
     pub undefined: TableRow,
-    pub order: Vec<String>
+    /// Total of all records. Only aggregated when required by the definition.
+    pub total: Option<TableRow>,
+    /// Merged rows beyond the limit. Computed on sort.
+    pub others: Option<TableRow>,
+    /// Distinct values of the pivot column in sorted order.
+    pub columns: BTreeSet<String>,
+    pub order: Vec<RowKey>
 }
 
 impl Table {
 
     pub fn new(definition: TableDef) -> Self {
+        let total = if definition.total { Some(TableRow::new()) } else { None };
         Self {
             definition,
             rows: HashMap::new(),
             undefined: TableRow::new(),
+            total,
+            others: None,
+            columns: BTreeSet::new(),
             order: Vec::new(),
         }
     }
 
     // Read one line from input reader and update row informations.
-    pub fn aggregate(&mut self, mut reader: Box<dyn BufRead>) {
-        loop {
-            let record = LogRecord::parse(
-                &mut reader, self.definition.key_accessor(), &self.definition.field_accessor()[..]);
+    pub fn aggregate(&mut self, reader: Box<dyn BufRead>) {
+        aggregate_tables(&mut [self], reader);
+    }
+
+    /// Aggregate json records, such as the rows of another table.
+    pub fn aggregate_records(&mut self, records: Vec<Value>) {
+        for (position, v) in records.into_iter().enumerate() {
+            self.add(v, position as u64);
+        }
+        self.fill_gaps();
+    }
+
+    /// Output rows of groups as json records keyed by index and value column names,
+    /// so that another table can aggregate them.
+    /// Subtotal, others, undefined and total rows are not included.
+    /// Seconds are written with the `s` suffix, to be read back with `dtype: second`.
+    pub fn records(&mut self) -> Vec<Value> {
+        let def = self.definition.clone();
+        let names = self.value_names();
+        let mut result: Vec<Value> = vec![];
+        for (kind, key, values) in self.output_rows() {
+            if kind != RowKind::Group {
+                continue;
+            }
+            let mut obj = Map::new();
+            for (name, k) in def.index_names().iter().zip(key) {
+                obj.insert(name.to_string(), Value::String(k));
+            }
+            for (name, v) in names.iter().zip(values.iter()) {
+                let v = match v {
+                    LogValue::Second(x) => Value::String(format!("{}s", x)),
+                    _ => v.to_json(),
+                };
+                obj.insert(name.clone(), v);
+            }
+            result.push(Value::Object(obj));
+        }
+        result
+    }
 
+    /// Update rows with the json of a record at `position` of the input.
+    fn add(&mut self, v: Value, position: u64) {
+        let mut r = LogRecord::from_json(
+            v, &self.definition.key_accessors()[..], &self.definition.field_accessor()[..]);
+        r.position = position;
+        if !self.definition.accepts(&r) {
+            return;
+        }
+        r.key = r.key.and_then(|k| self.definition.make_key(k));
+        let column = self.definition.column_key(&r);
+        if let Some(c) = &column {
+            if !self.columns.contains(c) {
+                self.columns.insert(c.clone());
+            }
+        }
+        let column = column.as_deref();
 
-            if let Ok(r) = record {
+        if let Some(key) = &r.key {
+            // Subtotals are updated for every prefix of the key.
+            let min_len = if self.definition.rollup { 1 } else { key.len() };
+            for len in min_len..=key.len() {
+                let key = &key[..len];
+                // If not key in HashMap, Insert new record.
+                if !self.rows.contains_key(key) {
+                    self.rows.insert(key.to_vec(), TableRow::new());
+                }
+                // Update record.
+                if let Some(row) = self.rows.get_mut(key) {
+                    row.update_with_column(&r, column, &self.definition.fields)
+                } 
+            }
+        } else {
+            // When some of index values are not in json record.
+            self.undefined.update_with_column(&r, column, &self.definition.fields);
+        }
+        if let Some(total) = &mut self.total {
+            total.update_with_column(&r, column, &self.definition.fields);
+        }
+    }
+
+    /// Add empty rows for time buckets without records.
+    /// Gaps are filled for every observed combination of the other index values.
+    /// At most `MAX_FILLED_ROWS` rows are inserted, with a warning when more are needed.
+    fn fill_gaps(&mut self) {
+        let n = self.definition.index.len();
+        let mut inserted: usize = 0;
+        let mut truncated = false;
+        for (pos, index) in self.definition.index.iter().enumerate() {
+            let (bucket, fill) = match (&index.bucket, &index.fill) {
+                (Some(bucket), Some(fill)) => (bucket, fill),
+                _ => { continue; }
+            };
+            let full_keys: Vec<&RowKey> = self.rows.keys().filter(|k| k.len() == n).collect();
+            // One more label than the limit tells whether the range is cut off.
+            let mut labels = fill.labels(bucket, full_keys.iter().map(|k| &k[pos]), MAX_FILLED_ROWS + 1);
+            if labels.len() > MAX_FILLED_ROWS {
+                labels.truncate(MAX_FILLED_ROWS);
+                truncated = true;
+            }
+            let mut others: HashSet<RowKey> = HashSet::new();
+            for key in full_keys {
+                let mut other = key.clone();
+                other.remove(pos);
+                others.insert(other);
+            }
+            if others.is_empty() && fill.from.is_some() && fill.to.is_some() {
+                // Nothing observed. Only possible with a single index.
+                others.insert(vec![]);
+            }
 
-                if let Some(str_key) = &r.key {
-                    // If not str_key in HashMap, Insert new record.
-                    if !self.rows.contains_key(str_key) {
-                        self.rows.insert(str_key.to_string(), TableRow::new());
+            for other in others.iter().filter(|o| o.len() == n - 1) {
+                for label in labels.iter() {
+                    let mut key = other.clone();
+                    key.insert(pos, label.clone());
+                    // With rollup, subtotals containing the bucket are also needed.
+                    let min_len = if self.definition.rollup { pos + 1 } else { n };
+                    for len in min_len..=n {
+                        if self.rows.contains_key(&key[..len]) {
+                            continue;
+                        }
+                        if inserted >= MAX_FILLED_ROWS {
+                            truncated = true;
+                            break;
+                        }
+                        self.rows.insert(key[..len].to_vec(), TableRow::with_fields(&self.definition.fields));
+                        inserted += 1;
                     }
-                    // Update record.
-                    if let Some(row) = self.rows.get_mut(str_key) {
-                        row.update(&r, &self.definition.fields)
-                    } 
-                } else {
-                    // When index value is not in json record.
-                    self.undefined.update(&r, &self.definition.fields);
                 }
-            } else {
-                break;
             }
         }
+        if truncated {
+            eprintln!("Warning: gaps are filled with only {} rows. Narrow the range with from and to.", MAX_FILLED_ROWS);
+        }
     }
 
-    /// Sort rows according to table definition.
+    /// Sort rows according to table definition, dropping rows which do not match `having`.
+    /// With rollup, each subtotal row is followed by its sorted children.
+    /// Top level rows beyond the limit are merged into the others row.
     fn sort(&mut self) {
-        if let Some(x) = &self.definition.order_by {
+        // Group keys by their parent. Without rollup all keys are at the top level.
+        let mut children: HashMap<&[String], Vec<&RowKey>> = HashMap::new();
+        for key in self.rows.keys() {
+            if !self.matches_having(key) {
+                continue;
+            }
+            let parent = if self.definition.rollup { &key[..key.len() - 1] } else { &key[..0] };
+            children.entry(parent).or_default().push(key);
+        }
+        for keys in children.values_mut() {
+            self.sort_keys(keys);
+        }
+        if let Some(n) = self.definition.limit_per_group {
+            if self.definition.rollup {
+                // Rows of the deepest level are the children of each group.
+                let depth = self.definition.index.len() - 1;
+                for (parent, keys) in children.iter_mut() {
+                    if parent.len() == depth {
+                        keys.truncate(n);
+                    }
+                }
+            } else if let Some(keys) = children.get_mut(&[][..]) {
+                *keys = limit_per_group(&self.definition.index, keys, n);
+            }
+        }
 
-            let mut tmp_order :Vec<(&str, LogValue)> = vec![];
-            for (key, row) in self.rows.iter() {
-                let v = row.get(x);
-                tmp_order.push((key, v));
+        let mut order: Vec<RowKey> = vec![];
+        let mut stack: Vec<&RowKey> = vec![];
+        let mut dropped: Vec<RowKey> = vec![];
+        if let Some(keys) = children.get(&[][..]) {
+            let limit = self.definition.limit.unwrap_or(keys.len()).min(keys.len());
+            stack.extend(keys[..limit].iter().rev());
+            dropped.extend(keys[limit..].iter().map(|k| (*k).clone()));
+        }
+        while let Some(key) = stack.pop() {
+            order.push(key.clone());
+            if !self.definition.rollup {
+                continue;
+            }
+            if let Some(keys) = children.get(&key[..]) {
+                stack.extend(keys.iter().rev());
             }
+        }
+        self.order = order;
 
-            if self.definition.ascending {
-                tmp_order.sort_by(|a, b| { a.1.cmp(&b.1) });
-            } else {
-                tmp_order.sort_by(|a, b| { b.1.cmp(&a.1) });
+        self.others = match self.definition.limit {
+            Some(_) => {
+                let mut others = TableRow::new();
+                for key in dropped.iter() {
+                    if let Some(row) = self.rows.get(key) {
+                        others.merge(row, &self.definition.fields);
+                    }
+                }
+                Some(others)
+            },
+            None => None,
+        };
+    }
+
+    /// Whether the row matches `having`. Identifiers are resolved to the value of the field,
+    /// or to the value of the index of the same name.
+    fn matches_having(&self, key: &RowKey) -> bool {
+        let (having, row) = match (&self.definition.having, self.rows.get(key)) {
+            (Some(having), Some(row)) => (having, row),
+            _ => { return true; }
+        };
+        having.eval(&|name: &str| {
+            if let Some(f) = self.definition.fields.iter().find(|f| f.name() == name) {
+                return row.get_value(f, &self.definition.fields);
+            }
+            match self.definition.index.iter().position(|i| i.name() == name) {
+                Some(i) => key.get(i).map_or(LogValue::None, |k| LogValue::String(k.clone())),
+                None => LogValue::None,
             }
+        })
+    }
 
-            let mut order: Vec<String> = vec![];
-            for (key, _) in tmp_order {
-                order.push(key.to_string());
+    /// Sort sibling keys by each of `order_by` in turn.
+    /// Ties are broken by the natural order of keys, so the output is deterministic.
+    fn sort_keys(&self, keys: &mut [&RowKey]) {
+        let order_by = &self.definition.order_by;
+        let mut tmp_order :Vec<(&RowKey, Vec<LogValue>)> = vec![];
+        for key in keys.iter() {
+            let mut values: Vec<LogValue> = vec![];
+            for s in order_by.iter() {
+                values.push(match &s.by {
+                    OrderBy::Index(_) => LogValue::None,
+                    OrderBy::Field(f) => self.rows.get(*key)
+                        .map_or(LogValue::None, |row| row.get_value(f, &self.definition.fields)),
+                });
             }
-            self.order = order;
-        } else {
-            let mut order: Vec<String> = vec![];
-            for (key, _) in self.rows.iter() {
-                order.push(key.clone());
-                order.sort();
+            tmp_order.push((key, values));
+        }
+
+        tmp_order.sort_by(|a, b| {
+            for (i, s) in order_by.iter().enumerate() {
+                let ord = match s.by {
+                    OrderBy::Index(x) => match (a.0.get(x), b.0.get(x)) {
+                        (Some(v), Some(w)) => index_cmp(&self.definition.index, x, v, w),
+                        (x, y) => x.cmp(&y),
+                    },
+                    OrderBy::Field(_) => a.1[i].cmp(&b.1[i]),
+                };
+                let ord = if s.ascending { ord } else { ord.reverse() };
+                if ord != Ordering::Equal {
+                    return ord;
+                }
             }
-            self.order = order;
+            key_cmp(&self.definition.index, a.0, b.0)
+        });
+
+        for (i, (key, _)) in tmp_order.into_iter().enumerate() {
+            keys[i] = key;
         }
     }
 
-    pub fn sorted_rows(&mut self) -> Vec<(&str, &TableRow)>{
+    /// Names of value columns. Field names, or a column for each field and pivot value.
+    pub fn value_names(&self) -> Vec<String> {
+        let fields = &self.definition.fields;
+        if self.definition.columns.is_none() {
+            return fields.iter().map(|f| f.name().to_string()).collect();
+        }
+        let mut result: Vec<String> = vec![];
+        for f in fields.iter() {
+            for c in self.columns.iter() {
+                // The pivot value alone is enough when there is only one field.
+                if fields.len() == 1 {
+                    result.push(c.clone());
+                } else {
+                    result.push(format!("{}:{}", f.name(), c));
+                }
+            }
+        }
+        result
+    }
+
+    /// Field of each value column, in the order of `value_names`.
+    pub fn value_fields(&self) -> Vec<&Field> {
+        let repeat = if self.definition.columns.is_some() { self.columns.len() } else { 1 };
+        self.definition.fields.iter()
+            .flat_map(|f| std::iter::repeat_n(f, repeat))
+            .collect()
+    }
+
+    /// Sorted rows with the values to display.
+    /// Values of fields with `percent` or `cumulative` are converted here, as they depend on other rows.
+    pub fn output_rows(&mut self) -> Vec<(RowKind, RowKey, Vec<LogValue>)> {
+        let def = self.definition.clone();
+        let columns = self.columns.clone();
+        let fields: Vec<Field> = self.value_fields().into_iter().cloned().collect();
+        let top_len = if def.rollup { 1 } else { def.index.len() };
+
+        // Column totals over all records, regardless of `having` and `limit`.
+        let mut totals: Vec<f64> = vec![0.; fields.len()];
+        if fields.iter().any(|f| f.percent) {
+            let top_rows = self.rows.iter().filter(|(k, _)| k.len() == top_len).map(|(_, row)| row);
+            for row in top_rows.chain(std::iter::once(&self.undefined)) {
+                for (t, v) in totals.iter_mut().zip(row.get_values(&def, &columns)) {
+                    *t += v.as_f64().unwrap_or(0.);
+                }
+            }
+        }
+
+        // Running sums for the rows of each parent, so that rows of each subtotal start from zero.
+        let mut running: HashMap<RowKey, Vec<f64>> = HashMap::new();
+        let mut result: Vec<(RowKind, RowKey, Vec<LogValue>)> = vec![];
+        for (kind, key, row) in self.sorted_rows() {
+            let mut values = row.get_values(&def, &columns);
+            let parent = match kind {
+                RowKind::Group | RowKind::Subtotal if def.rollup => Some(key[..key.len() - 1].to_vec()),
+                RowKind::Group | RowKind::Subtotal | RowKind::Others => Some(vec![]),
+                RowKind::Undefined | RowKind::Total => None,
+            };
+            for (i, (f, v)) in fields.iter().zip(values.iter_mut()).enumerate() {
+                let mut x = match v.as_f64() {
+                    Some(x) => x,
+                    None => { continue; }
+                };
+                if let (true, Some(p)) = (f.cumulative, &parent) {
+                    let sums = running.entry(p.clone()).or_insert_with(|| vec![0.; fields.len()]);
+                    sums[i] += x;
+                    x = sums[i];
+                }
+                *v = if f.percent {
+                    if totals[i] == 0. { LogValue::None } else { LogValue::Float(x / totals[i] * 100.) }
+                } else {
+                    match v {
+                        LogValue::Second(_) => LogValue::Second(x),
+                        LogValue::Integer(_) => LogValue::Integer(x as u32),
+                        _ => LogValue::Float(x),
+                    }
+                };
+            }
+            result.push((kind, key, values));
+        }
+        result
+    }
+
+    pub fn sorted_rows(&mut self) -> Vec<(RowKind, RowKey, &TableRow)>{
         self.sort();
-        let mut result :Vec<(&str, &TableRow)> = vec![];
+        let n = self.definition.index.len();
+        let mut result :Vec<(RowKind, RowKey, &TableRow)> = vec![];
         for key in self.order.iter() {
             if let Some(v) = self.rows.get(key) {
-                result.push((key, v));
+                let kind = if key.len() < n { RowKind::Subtotal } else { RowKind::Group };
+                result.push((kind, key.clone(), v));
+            }
+        }
+        if let Some(others) = &self.others {
+            result.push((RowKind::Others, vec![], others));
+        }
+        // The undefined row is omitted when all records have index values.
+        if !self.undefined.is_empty() {
+            match self.definition.undefined_position {
+                RowPosition::First => result.insert(0, (RowKind::Undefined, vec![], &self.undefined)),
+                RowPosition::Last => result.push((RowKind::Undefined, vec![], &self.undefined)),
+                RowPosition::Hidden => {},
             }
         }
-        result.push(("undefined", &self.undefined));
+        if let Some(total) = &self.total {
+            result.push((RowKind::Total, vec![], total));
+        }
         result
     }
 
 }
 
+/// Keep the first `n` keys of each group of keys sharing all but the last index.
+/// Groups are placed in the order of their outer indexes.
+fn limit_per_group<'a>(index: &[Index], keys: &[&'a RowKey], n: usize) -> Vec<&'a RowKey> {
+    let mut groups: Vec<(&[String], Vec<&RowKey>)> = vec![];
+    let mut positions: HashMap<&[String], usize> = HashMap::new();
+    for key in keys.iter() {
+        let outer = &key[..key.len() - 1];
+        let i = *positions.entry(outer).or_insert_with(|| {
+            groups.push((outer, vec![]));
+            groups.len() - 1
+        });
+        if groups[i].1.len() < n {
+            groups[i].1.push(key);
+        }
+    }
+    groups.sort_by(|a, b| key_cmp(index, a.0, b.0));
+    groups.into_iter().flat_map(|(_, keys)| keys).collect()
+}
+
+/// Compare keys in the order of each index value.
+fn key_cmp(index: &[Index], a: &[String], b: &[String]) -> Ordering {
+    for (pos, (x, y)) in a.iter().zip(b.iter()).enumerate() {
+        let ord = index_cmp(index, pos, x, y);
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+/// Compare values of the index at `pos`. Time buckets are compared by their start time,
+/// as labels with different offsets do not sort chronologically. Others are in natural order.
+fn index_cmp(index: &[Index], pos: usize, x: &str, y: &str) -> Ordering {
+    if index.get(pos).is_some_and(|i| i.bucket.is_some()) {
+        if let Some(ord) = time_bucket::compare_labels(x, y) {
+            return ord;
+        }
+    }
+    natural_cmp(x, y)
+}
+
+/// Compare strings treating runs of digits as numbers, so that `v2` comes before `v10`.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+            let (si, sj) = (i, j);
+            while i < a.len() && a[i].is_ascii_digit() {
+                i += 1;
+            }
+            while j < b.len() && b[j].is_ascii_digit() {
+                j += 1;
+            }
+            // Compare numbers by the length without leading zeros, then digit by digit.
+            let x = trim_zeros(&a[si..i]);
+            let y = trim_zeros(&b[sj..j]);
+            let ord = x.len().cmp(&y.len()).then_with(|| x.cmp(y));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+        } else {
+            if a[i] != b[j] {
+                return a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+    }
+    // Shorter one first, then the plain byte order such as `01` and `1`.
+    (a.len() - i).cmp(&(b.len() - j)).then_with(|| a.cmp(b))
+}
+
+fn trim_zeros(digits: &[u8]) -> &[u8] {
+    let n = digits.iter().take_while(|d| **d == b'0').count();
+    &digits[n..]
+}
+
+/// Read records from the reader once and aggregate them into every table.
+pub fn aggregate_tables(tables: &mut [&mut Table], mut reader: Box<dyn BufRead>) {
+    let mut position: u64 = 0;
+    while let Ok(v) = LogRecord::read_json(&mut reader) {
+        if let Some((last, others)) = tables.split_last_mut() {
+            for table in others.iter_mut() {
+                table.add(v.clone(), position);
+            }
+            last.add(v, position);
+        }
+        position += 1;
+    }
+    for table in tables.iter_mut() {
+        table.fill_gaps();
+    }
+}
+
 /// Struct which describe table row.
 pub struct TableRow {
     // row name -> value
     values: HashMap<String, Box<dyn Operation>>,
+    // pivot value -> cells of the pivot column
+    cells: HashMap<String, TableRow>,
 }
 
 impl Default for TableRow {
@@ -113,9 +552,19 @@ impl TableRow {
     pub fn new() -> Self{
         Self {
             values: HashMap::new(),
+            cells: HashMap::new(),
         }
     }
 
+    /// Row without records, whose operations are initialized. e.g. count is 0.
+    pub fn with_fields(fields: &[Field]) -> Self {
+        let mut row = Self::new();
+        for f in fields {
+            row.values.insert(f.name().to_string(), build_operation(&f.op_type));
+        }
+        row
+    }
+
     pub fn update(&mut self, record: &LogRecord, fields: &[Field]) {
         for f in fields {
             // Insert field if not exist.
@@ -124,7 +573,23 @@ impl TableRow {
 
             let v = record.get(f.name());
             if let Some(op) = self.values.get_mut(f.name()) {
-                op.update(&v);
+                match &f.weight {
+                    Some(w) => op.update_weighted(&v, weight_of(record, w), record),
+                    None => op.update_with_record(&v, record),
+                }
+            }
+        }
+    }
+
+    /// Update the row, and the cells of the pivot column if `column` is given.
+    pub fn update_with_column(&mut self, record: &LogRecord, column: Option<&str>, fields: &[Field]) {
+        self.update(record, fields);
+        if let Some(c) = column {
+            if !self.cells.contains_key(c) {
+                self.cells.insert(c.to_string(), TableRow::new());
+            }
+            if let Some(cell) = self.cells.get_mut(c) {
+                cell.update(record, fields);
             }
         }
     }
@@ -138,33 +603,127 @@ impl TableRow {
         }
     }
 
+    /// Value of the field. Expressions are computed from other `fields` of the row.
+    pub fn get_value(&self, field: &Field, fields: &[Field]) -> LogValue {
+        match &field.op_type {
+            OpType::Expression { expr } => {
+                let resolve = |name: &str| match fields.iter().find(|f| f.name() == name) {
+                    // Expressions only refer to preceding fields, so this terminates.
+                    Some(f) => self.get_value(f, fields),
+                    None => LogValue::None,
+                };
+                expr.eval(&resolve).map_or(LogValue::None, LogValue::Float)
+            },
+            _ => self.get(field),
+        }
+    }
+
     /// Extracts data from records in the order specified by `field` argument.
     pub fn get_row(&self, fields: &[Field]) -> Vec<LogValue> {
         let mut result: Vec<LogValue> = Vec::new();
         for f in fields {
-            let v = self.values.get(f.name()).map(|x| x.value());
-            if let Some(x) = v {
-                result.push(x);
-            } else {
-                result.push(LogValue::None);
+            result.push(self.get_value(f, fields));
+        }
+        result
+    }
+
+    /// Whether no records have been aggregated into the row.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Merge operations and pivot cells of another row into this row.
+    pub fn merge(&mut self, other: &TableRow, fields: &[Field]) {
+        for f in fields {
+            if let Some(x) = other.values.get(f.name()) {
+                self.values.entry(f.name().to_string())
+                    .or_insert_with(|| build_operation(&f.op_type))
+                    .merge(x.as_ref());
+            }
+        }
+        for (c, cell) in other.cells.iter() {
+            self.cells.entry(c.clone()).or_default().merge(cell, fields);
+        }
+    }
+
+    /// Values of each field and pivot value, in the order of `Table::value_names`.
+    /// Empty cells are values of an operation without records. e.g. count is 0.
+    pub fn get_cells(&self, fields: &[Field], columns: &BTreeSet<String>) -> Vec<LogValue> {
+        let empty = TableRow::with_fields(fields);
+        let mut result: Vec<LogValue> = Vec::new();
+        for f in fields {
+            for c in columns.iter() {
+                let cell = self.cells.get(c).unwrap_or(&empty);
+                result.push(cell.get_value(f, fields));
             }
         }
         result
     }
+
+    /// Values of the row to display.
+    pub fn get_values(&self, def: &TableDef, columns: &BTreeSet<String>) -> Vec<LogValue> {
+        match def.columns {
+            Some(_) => self.get_cells(&def.fields, columns),
+            None => self.get_row(&def.fields),
+        }
+    }
 }
 
 
+/// Column to sort rows by.
+#[derive(Clone)]
+pub enum OrderBy {
+    /// Position of the index.
+    Index(usize),
+    Field(Field),
+}
+
+/// Column to sort rows by, with the direction.
+#[derive(Clone)]
+pub struct SortKey {
+    pub by: OrderBy,
+    pub ascending: bool,
+}
+
 #[derive(Clone)]
 pub struct TableDef {
-    pub index: Index,
+    pub index: Vec<Index>,
     pub fields: Vec<Field>,
-    pub order_by: Option<Field>,
-    pub ascending: bool, 
+    /// Sort keys applied in turn. Rows are sorted by keys at last.
+    pub order_by: Vec<SortKey>,
+    /// Add subtotal rows for each prefix of the key and a grand total row.
+    pub rollup: bool,
+    /// Spread distinct values of the index into value columns, as a crosstab.
+    pub columns: Option<Index>,
+    /// Only records matching the predicate are aggregated.
+    pub filter: Option<Predicate>,
+    /// Only rows whose aggregated values match the predicate are output.
+    pub having: Option<Predicate>,
+    /// Number of top level rows to output. The rest are merged into the others row.
+    pub limit: Option<usize>,
+    /// Number of rows to output for each value of the outer indexes, which are all but the last index.
+    /// The rest are dropped.
+    pub limit_per_group: Option<usize>,
+    /// Add the grand total row.
+    pub total: bool,
+    /// Label of the row and the pivot column for records without index values.
+    pub undefined_label: String,
+    pub undefined_position: RowPosition,
+}
+
+/// Where to place a row in the output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RowPosition {
+    First,
+    Last,
+    Hidden,
 }
 
 impl TableDef {
-    pub fn new(index: Index, fields: Vec<Field>, order_by: Option<Field>, ascending: bool) -> Self {
-        Self { index, fields, order_by, ascending}
+    pub fn new(index: Vec<Index>, fields: Vec<Field>, order_by: Option<OrderBy>, ascending: bool) -> Self {
+        let order_by = order_by.map(|by| SortKey { by, ascending }).into_iter().collect();
+        Self { index, fields, order_by, rollup: false, columns: None, filter: None, having: None, limit: None, limit_per_group: None, total: false,
+            undefined_label: String::from("undefined"), undefined_position: RowPosition::Last }
     }
 
     pub fn field_accessor(&self) -> Vec<&Accessor> {
@@ -175,8 +734,66 @@ impl TableDef {
         result
     }
 
-    pub fn key_accessor(&self) -> &Accessor {
-        &self.index.accessor
+    pub fn key_accessors(&self) -> Vec<&Accessor> {
+        let mut result :Vec<&Accessor> = Vec::new();
+        for i in self.index.iter() {
+            result.push(&i.accessor);
+        }
+        result
+    }
+
+    /// Convert index values read from the record into the key of the row.
+    /// Returns `None` if some of values can not be converted.
+    pub fn make_key(&self, values: Vec<String>) -> Option<RowKey> {
+        let mut key: RowKey = Vec::with_capacity(values.len());
+        for (index, v) in self.index.iter().zip(values) {
+            match &index.bucket {
+                Some(bucket) => key.push(bucket.key(&v)?),
+                None => key.push(v),
+            }
+        }
+        Some(key)
+    }
+
+    /// Whether the record passes the filter.
+    pub fn accepts(&self, record: &LogRecord) -> bool {
+        match &self.filter {
+            Some(p) => p.eval(&|path: &str| record.resolve(path)),
+            None => true,
+        }
+    }
+
+    /// Pivot value of the record. Records without the value go to the undefined column.
+    pub fn column_key(&self, record: &LogRecord) -> Option<String> {
+        let index = self.columns.as_ref()?;
+        let key = match record.lookup(&index.accessor) {
+            LogValue::None => None,
+            v => match &index.bucket {
+                Some(bucket) => bucket.key(&v.as_string()),
+                None => Some(v.as_string()),
+            },
+        };
+        Some(key.unwrap_or_else(|| self.undefined_label.clone()))
+    }
+
+    pub fn index_names(&self) -> Vec<&str> {
+        self.index.iter().map(|i| i.name()).collect()
+    }
+
+    /// Cells of index columns for the row. `None` for rolled up or missing indexes.
+    pub fn key_cells(&self, kind: RowKind, key: &[String]) -> Vec<Option<String>> {
+        let mut cells: Vec<Option<String>> = vec![None; self.index.len()];
+        match kind {
+            RowKind::Group | RowKind::Subtotal => {
+                for (cell, k) in cells.iter_mut().zip(key.iter()) {
+                    *cell = Some(k.clone());
+                }
+            },
+            RowKind::Total => cells[0] = Some(String::from("total")),
+            RowKind::Others => cells[0] = Some(String::from("others")),
+            RowKind::Undefined => cells[0] = Some(self.undefined_label.clone()),
+        }
+        cells
     }
 
     pub fn field_num(&self) -> usize {
@@ -184,15 +801,44 @@ impl TableDef {
     }
 }
 
+/// Weight of the record. Records without valid weight weigh 1.
+fn weight_of(record: &LogRecord, weight: &Accessor) -> f64 {
+    match record.lookup(weight).as_f64() {
+        Some(w) if w >= 0. => w,
+        _ => 1.
+    }
+}
+
 #[derive(Clone)]
 pub struct Field {
     pub accessor: Accessor,
-    pub op_type: OpType
+    pub op_type: OpType,
+    /// Property to weight each record, such as sample rate.
+    pub weight: Option<Accessor>,
+    /// Display the value as percent of the column total.
+    pub percent: bool,
+    /// Display the running sum of values in the sorted order.
+    pub cumulative: bool,
 }
 
 impl Field {
     pub fn new(accessor: Accessor, op_type: OpType) -> Self {
-        Self { accessor, op_type }
+        Self { accessor, op_type, weight: None, percent: false, cumulative: false }
+    }
+
+    pub fn with_weight(mut self, weight: Accessor) -> Self {
+        self.weight = Some(weight);
+        self
+    }
+
+    pub fn with_percent(mut self) -> Self {
+        self.percent = true;
+        self
+    }
+
+    pub fn with_cumulative(mut self) -> Self {
+        self.cumulative = true;
+        self
     }
 
     pub fn name(&self) -> &str {
@@ -202,16 +848,555 @@ impl Field {
 
 #[derive(Clone)]
 pub struct Index {
-    pub accessor: Accessor
+    pub accessor: Accessor,
+    /// Group timestamps into time buckets instead of grouping by value.
+    pub bucket: Option<TimeBucket>,
+    /// Add empty rows for buckets without records.
+    pub fill: Option<GapFill>,
 }
 
 impl Index {
     pub fn new(accessor: Accessor) -> Self {
-        Self { accessor }
+        Self { accessor, bucket: None, fill: None }
     }    
 
+    pub fn with_bucket(mut self, bucket: TimeBucket) -> Self {
+        self.bucket = Some(bucket);
+        self
+    }
+
+    pub fn with_fill(mut self, fill: GapFill) -> Self {
+        self.fill = Some(fill);
+        self
+    }
+
     pub fn name(&self) -> &str {
         &self.accessor.name
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expression::{ Expr };
+    use crate::log_record::{ LogValueType };
+    use std::io::{ Cursor };
+
+    fn build_table(lines: &[&str], rollup: bool) -> Table {
+        let index = vec![
+            Index::new(Accessor::from_string("service", "service", LogValueType::String)),
+            Index::new(Accessor::from_string("status", "status", LogValueType::String)),
+        ];
+        let count = Field::new(Accessor::from_string("count", "service", LogValueType::String), OpType::Count);
+        let mut def = TableDef::new(index, vec![count.clone()], Some(OrderBy::Field(count)), false);
+        def.rollup = rollup;
+        def.total = rollup;
+        let mut table = Table::new(def);
+        table.aggregate(Box::new(Cursor::new(lines.join("\n"))));
+        table
+    }
+
+    #[test]
+    fn check_time_bucket_index() {
+        let index = vec![
+            Index::new(Accessor::from_string("minute", "time", LogValueType::String))
+                .with_bucket(TimeBucket::parse("1m", None).unwrap()),
+        ];
+        let count = Field::new(Accessor::from_string("count", "time", LogValueType::String), OpType::Count);
+        let mut table = Table::new(TableDef::new(index, vec![count], None, true));
+        let lines = [
+            r#"{"time": "2022-06-05T12:01:30Z"}"#,
+            r#"{"time": "2022-06-05T12:00:10Z"}"#,
+            r#"{"time": "2022-06-05T12:01:59Z"}"#,
+            r#"{"time": "invalid"}"#,
+        ];
+        table.aggregate(Box::new(Cursor::new(lines.join("\n"))));
+        let fields = table.definition.fields.clone();
+        let rows: Vec<(RowKey, LogValue)> = table.sorted_rows().into_iter()
+            .map(|(_, key, row)| (key, row.get_row(&fields)[0].clone()))
+            .collect();
+        assert_eq!(rows[0], (vec![String::from("2022-06-05T12:00:00Z")], LogValue::Integer(1)));
+        assert_eq!(rows[1], (vec![String::from("2022-06-05T12:01:00Z")], LogValue::Integer(2)));
+        assert_eq!(rows[2], (vec![], LogValue::Integer(1)));
+    }
+
+    #[test]
+    fn check_fill_gaps() {
+        let index = vec![
+            Index::new(Accessor::from_string("minute", "time", LogValueType::String))
+                .with_bucket(TimeBucket::parse("1m", None).unwrap())
+                .with_fill(GapFill::default()),
+            Index::new(Accessor::from_string("method", "method", LogValueType::String)),
+        ];
+        let fields = vec![
+            Field::new(Accessor::from_string("count", "time", LogValueType::String), OpType::Count),
+            Field::new(Accessor::from_string("average", "latency", LogValueType::Float), OpType::Average),
+        ];
+        let mut table = Table::new(TableDef::new(index, fields.clone(), None, true));
+        let lines = [
+            r#"{"time": "2022-06-05T12:00:10Z", "method": "GET", "latency": "1"}"#,
+            r#"{"time": "2022-06-05T12:03:10Z", "method": "GET", "latency": "1"}"#,
+            r#"{"time": "2022-06-05T12:01:10Z", "method": "POST", "latency": "1"}"#,
+        ];
+        table.aggregate(Box::new(Cursor::new(lines.join("\n"))));
+        let rows: Vec<(RowKey, Vec<LogValue>)> = table.sorted_rows().into_iter()
+            .filter(|(kind, _, _)| *kind == RowKind::Group)
+            .map(|(_, key, row)| (key, row.get_row(&fields)))
+            .collect();
+        // 4 minutes for each method.
+        assert_eq!(rows.len(), 8);
+        assert_eq!(rows[1].0, vec!["2022-06-05T12:00:00Z", "POST"]);
+        assert_eq!(rows[1].1[0], LogValue::Integer(0));
+        assert!(matches!(rows[1].1[1], LogValue::None));
+        assert_eq!(rows[3].1, vec![LogValue::Integer(1), LogValue::Float(1.)]);
+
+        // Rows inserted for all combinations of indexes are limited.
+        let index = vec![
+            Index::new(Accessor::from_string("second", "time", LogValueType::String))
+                .with_bucket(TimeBucket::parse("1s", None).unwrap())
+                .with_fill(GapFill::default()),
+            Index::new(Accessor::from_string("method", "method", LogValueType::String)),
+        ];
+        let mut table = Table::new(TableDef::new(index, fields, None, true));
+        let lines = [
+            r#"{"time": "2022-06-05T00:00:00Z", "method": "GET"}"#,
+            r#"{"time": "2022-06-05T12:00:00Z", "method": "POST"}"#,
+            r#"{"time": "2022-06-05T12:00:00Z", "method": "PUT"}"#,
+        ];
+        table.aggregate(Box::new(Cursor::new(lines.join("\n"))));
+        assert_eq!(table.rows.len(), 3 + MAX_FILLED_ROWS);
+    }
+
+    #[test]
+    fn check_filter() {
+        let mut table = build_table(&[], false);
+        table.definition.filter = Some(Predicate::parse("status >= 500 and service != web").unwrap());
+        let lines = [
+            r#"{"service": "api", "status": 500}"#,
+            r#"{"service": "api", "status": 200}"#,
+            r#"{"service": "web", "status": 503}"#,
+        ];
+        table.aggregate(Box::new(Cursor::new(lines.join("\n"))));
+        let key = vec![String::from("api"), String::from("500")];
+        assert_eq!(table.rows.keys().collect::<Vec<_>>(), vec![&key]);
+    }
+
+    #[test]
+    fn check_pivot() {
+        let index = vec![Index::new(Accessor::from_string("service", "service", LogValueType::String))];
+        let fields = vec![Field::new(Accessor::from_string("count", "service", LogValueType::String), OpType::Count)];
+        let mut def = TableDef::new(index, fields.clone(), None, true);
+        def.columns = Some(Index::new(Accessor::from_string("status", "status", LogValueType::String)));
+        let mut table = Table::new(def);
+        let lines = [
+            r#"{"service": "api", "status": 200}"#,
+            r#"{"service": "api", "status": 500}"#,
+            r#"{"service": "api", "status": 200}"#,
+            r#"{"service": "web", "status": 200}"#,
+            r#"{"service": "web"}"#,
+        ];
+        table.aggregate(Box::new(Cursor::new(lines.join("\n"))));
+        assert_eq!(table.value_names(), vec!["200", "500", "undefined"]);
+
+        let columns = table.columns.clone();
+        let rows: Vec<(RowKey, Vec<LogValue>)> = table.sorted_rows().into_iter()
+            .filter(|(kind, _, _)| *kind == RowKind::Group)
+            .map(|(_, key, row)| (key, row.get_cells(&fields, &columns)))
+            .collect();
+        assert_eq!(rows[0].1, vec![LogValue::Integer(2), LogValue::Integer(1), LogValue::Integer(0)]);
+        assert_eq!(rows[1].1, vec![LogValue::Integer(1), LogValue::Integer(0), LogValue::Integer(1)]);
+    }
+
+    #[test]
+    fn check_rollup() {
+        let lines = [
+            r#"{"service": "api", "status": "200"}"#,
+            r#"{"service": "api", "status": "200"}"#,
+            r#"{"service": "api", "status": "500"}"#,
+            r#"{"service": "web", "status": "200"}"#,
+            r#"{"service": "web", "status": "404"}"#,
+            r#"{"service": "web", "status": "404"}"#,
+            r#"{"service": "web", "status": "404"}"#,
+            r#"{"service": "web"}"#,
+        ];
+        let mut table = build_table(&lines, true);
+        let fields = table.definition.fields.clone();
+        let rows: Vec<(RowKind, RowKey, LogValue)> = table.sorted_rows().into_iter()
+            .map(|(kind, key, row)| (kind, key, row.get_row(&fields)[0].clone()))
+            .collect();
+        let expected = vec![
+            (RowKind::Subtotal, vec!["web"], 4),
+            (RowKind::Group, vec!["web", "404"], 3),
+            (RowKind::Group, vec!["web", "200"], 1),
+            (RowKind::Subtotal, vec!["api"], 3),
+            (RowKind::Group, vec!["api", "200"], 2),
+            (RowKind::Group, vec!["api", "500"], 1),
+            (RowKind::Undefined, vec![], 1),
+            (RowKind::Total, vec![], 8),
+        ];
+        assert_eq!(rows.len(), expected.len());
+        for ((kind, key, v), (e_kind, e_key, e_count)) in rows.into_iter().zip(expected) {
+            assert_eq!(kind, e_kind);
+            assert_eq!(key, e_key);
+            assert_eq!(v, LogValue::Integer(e_count));
+        }
+
+        // Without rollup, only groups and undefined rows are returned.
+        let mut table = build_table(&lines, false);
+        let kinds: Vec<RowKind> = table.sorted_rows().iter().map(|(kind, _, _)| *kind).collect();
+        assert_eq!(kinds.len(), 5);
+        assert!(kinds[..4].iter().all(|k| *k == RowKind::Group));
+    }
+
+    #[test]
+    fn check_limit() {
+        let lines = [
+            r#"{"service": "api", "status": "200"}"#,
+            r#"{"service": "api", "status": "200"}"#,
+            r#"{"service": "api", "status": "200"}"#,
+            r#"{"service": "api", "status": "500"}"#,
+            r#"{"service": "web", "status": "404"}"#,
+            r#"{"service": "web", "status": "404"}"#,
+            r#"{"service": "web", "status": "200"}"#,
+        ];
+        let mut table = build_table(&lines, false);
+        table.definition.limit = Some(2);
+        let fields = table.definition.fields.clone();
+        let rows: Vec<(RowKind, RowKey, LogValue)> = table.sorted_rows().into_iter()
+            .map(|(kind, key, row)| (kind, key, row.get_row(&fields)[0].clone()))
+            .collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[1].1, vec!["web", "404"]);
+        assert_eq!(rows[2].0, RowKind::Others);
+        assert_eq!(rows[2].2, LogValue::Integer(2));
+
+        // With rollup, subtotals are limited with their children.
+        let mut table = build_table(&lines, true);
+        table.definition.limit = Some(1);
+        let rows: Vec<(RowKind, RowKey, LogValue)> = table.sorted_rows().into_iter()
+            .map(|(kind, key, row)| (kind, key, row.get_row(&fields)[0].clone()))
+            .collect();
+        let kinds: Vec<RowKind> = rows.iter().map(|(kind, _, _)| *kind).collect();
+        assert_eq!(kinds, vec![RowKind::Subtotal, RowKind::Group, RowKind::Group,
+            RowKind::Others, RowKind::Total]);
+        assert_eq!(rows[3].2, LogValue::Integer(3));
+    }
+
+    #[test]
+    fn check_total_and_undefined() {
+        let index = vec![Index::new(Accessor::from_string("service", "service", LogValueType::String))];
+        let fields = vec![Field::new(Accessor::from_string("count", "status", LogValueType::String), OpType::Count)];
+        let mut def = TableDef::new(index, fields.clone(), None, true);
+        def.total = true;
+        def.undefined_label = String::from("(none)");
+        def.undefined_position = RowPosition::First;
+        let lines = [
+            r#"{"service": "api", "status": "200"}"#,
+            r#"{"service": "web", "status": "200"}"#,
+            r#"{"status": "500"}"#,
+        ];
+        let mut table = Table::new(def.clone());
+        table.aggregate(Box::new(Cursor::new(lines.join("\n"))));
+        let rows: Vec<(Vec<Option<String>>, LogValue)> = table.sorted_rows().into_iter()
+            .map(|(kind, key, row)| (def.key_cells(kind, &key), row.get_row(&fields)[0].clone()))
+            .collect();
+        assert_eq!(rows[0], (vec![Some(String::from("(none)"))], LogValue::Integer(1)));
+        assert_eq!(rows[3], (vec![Some(String::from("total"))], LogValue::Integer(3)));
+
+        // The undefined row is omitted when nothing is missing, or when hidden.
+        let mut table = Table::new(def.clone());
+        table.aggregate(Box::new(Cursor::new(lines[..2].join("\n"))));
+        assert_eq!(table.sorted_rows().len(), 3);
+        def.undefined_position = RowPosition::Hidden;
+        let mut table = Table::new(def);
+        table.aggregate(Box::new(Cursor::new(lines.join("\n"))));
+        assert!(table.sorted_rows().iter().all(|(kind, _, _)| *kind != RowKind::Undefined));
+    }
+
+    #[test]
+    fn check_percent_and_cumulative() {
+        let index = vec![Index::new(Accessor::from_string("service", "service", LogValueType::String))];
+        let count = Field::new(Accessor::from_string("count", "service", LogValueType::String), OpType::Count);
+        let fields = vec![
+            count.clone(),
+            Field::new(Accessor::from_string("share", "service", LogValueType::String), OpType::Count).with_percent(),
+            Field::new(Accessor::from_string("cum", "service", LogValueType::String), OpType::Count).with_cumulative(),
+            Field::new(Accessor::from_string("cum_share", "service", LogValueType::String), OpType::Count)
+                .with_percent().with_cumulative(),
+        ];
+        let mut def = TableDef::new(index, fields, Some(OrderBy::Field(count.clone())), false);
+        def.limit = Some(2);
+        def.total = true;
+        let mut table = Table::new(def);
+        let lines = ["api", "api", "api", "web", "web", "db", "api", "web", "batch", "db"]
+            .map(|s| format!(r#"{{"service": "{}"}}"#, s));
+        table.aggregate(Box::new(Cursor::new(lines.join("\n"))));
+        let rows: Vec<Vec<LogValue>> = table.output_rows().into_iter().map(|(_, _, v)| v).collect();
+        assert_eq!(rows[0], vec![LogValue::Integer(4), LogValue::Float(40.), LogValue::Integer(4), LogValue::Float(40.)]);
+        assert_eq!(rows[1], vec![LogValue::Integer(3), LogValue::Float(30.), LogValue::Integer(7), LogValue::Float(70.)]);
+        // Others row continues the running sum, and the total is not cumulated.
+        assert_eq!(rows[2], vec![LogValue::Integer(3), LogValue::Float(30.), LogValue::Integer(10), LogValue::Float(100.)]);
+        assert_eq!(rows[3], vec![LogValue::Integer(10), LogValue::Float(100.), LogValue::Integer(10), LogValue::Float(100.)]);
+
+        // With rollup, rows of each subtotal are cumulated from zero.
+        let index = vec![
+            Index::new(Accessor::from_string("service", "service", LogValueType::String)),
+            Index::new(Accessor::from_string("status", "status", LogValueType::String)),
+        ];
+        let fields = vec![
+            count.clone(),
+            Field::new(Accessor::from_string("cum", "service", LogValueType::String), OpType::Count).with_cumulative(),
+        ];
+        let mut def = TableDef::new(index, fields, Some(OrderBy::Field(count)), false);
+        def.rollup = true;
+        def.total = true;
+        let mut table = Table::new(def);
+        let lines = [("api", 200), ("api", 200), ("api", 500), ("web", 500), ("web", 200), ("api", 200), ("web", 500)]
+            .map(|(s, x)| format!(r#"{{"service": "{}", "status": "{}"}}"#, s, x));
+        table.aggregate(Box::new(Cursor::new(lines.join("\n"))));
+        let cum: Vec<LogValue> = table.output_rows().into_iter().map(|(_, _, v)| v[1].clone()).collect();
+        assert_eq!(cum, [4, 3, 4, 7, 2, 3, 7].map(LogValue::Integer).to_vec());
+    }
+
+    #[test]
+    fn check_expression_field() {
+        let index = vec![Index::new(Accessor::from_string("service", "service", LogValueType::String))];
+        let rate = Field::new(Accessor::from_string("rate", "", LogValueType::None),
+            OpType::Expression { expr: Expr::parse("errors / count").unwrap() });
+        let fields = vec![
+            Field::new(Accessor::from_string("count", "service", LogValueType::String), OpType::Count),
+            Field::new(Accessor::from_string("errors", "status", LogValueType::Integer),
+                OpType::CountIf { condition: Predicate::parse("status >= 500").unwrap() }),
+            rate.clone(),
+        ];
+        let mut table = Table::new(TableDef::new(index, fields, Some(OrderBy::Field(rate)), false));
+        let lines = [
+            r#"{"service": "api", "status": 200}"#,
+            r#"{"service": "api", "status": 500}"#,
+            r#"{"service": "web", "status": 500}"#,
+            r#"{"service": "web", "status": 200}"#,
+            r#"{"service": "web", "status": 200}"#,
+            r#"{"service": "web", "status": 200}"#,
+        ];
+        table.aggregate(Box::new(Cursor::new(lines.join("\n"))));
+        // Rows are sorted by the computed value.
+        let rows: Vec<(RowKey, Vec<LogValue>)> = table.output_rows().into_iter()
+            .map(|(_, key, values)| (key, values))
+            .collect();
+        assert_eq!(rows[0].0, vec!["api"]);
+        assert_eq!(rows[0].1[2], LogValue::Float(0.5));
+        assert_eq!(rows[1].1, vec![LogValue::Integer(4), LogValue::Integer(1), LogValue::Float(0.25)]);
+    }
+
+    #[test]
+    fn check_aggregate_tables() {
+        let mut by_service = build_table(&[], false);
+        let mut errors = build_table(&[], false);
+        errors.definition.filter = Some(Predicate::parse("status >= 500").unwrap());
+        let lines = [
+            r#"{"service": "api", "status": "200"}"#,
+            r#"{"service": "api", "status": "500"}"#,
+            r#"{"service": "web", "status": "200"}"#,
+        ];
+        aggregate_tables(&mut [&mut by_service, &mut errors], Box::new(Cursor::new(lines.join("\n"))));
+        assert_eq!(by_service.rows.len(), 3);
+        assert_eq!(errors.rows.len(), 1);
+    }
+
+    #[test]
+    fn check_aggregate_records() {
+        let lines = [
+            r#"{"service": "api", "status": "200"}"#,
+            r#"{"service": "api", "status": "200"}"#,
+            r#"{"service": "api", "status": "500"}"#,
+            r#"{"service": "web", "status": "200"}"#,
+        ];
+        let mut source = build_table(&lines, true);
+        let records = source.records();
+        // Only rows of groups are records.
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0], serde_json::json!({"service": "api", "status": "200", "count": 2}));
+
+        // Average count of records per status of each service.
+        let index = vec![Index::new(Accessor::from_string("service", "service", LogValueType::String))];
+        let average = Field::new(Accessor::from_string("count", "count", LogValueType::Float), OpType::Average);
+        let mut table = Table::new(TableDef::new(index, vec![average], None, true));
+        table.aggregate_records(records);
+        let rows: Vec<(RowKey, Vec<LogValue>)> = table.output_rows().into_iter()
+            .map(|(_, key, values)| (key, values))
+            .collect();
+        assert_eq!(rows, vec![
+            (vec!["api".to_string()], vec![LogValue::Float(1.5)]),
+            (vec!["web".to_string()], vec![LogValue::Float(1.)]),
+        ]);
+
+        // Seconds are read back as seconds.
+        let lines = [
+            r#"{"service": "api", "user": "a", "latency": "0.5s"}"#,
+            r#"{"service": "api", "user": "a", "latency": "1.5s"}"#,
+            r#"{"service": "api", "user": "b", "latency": "3s"}"#,
+        ];
+        let index = vec![
+            Index::new(Accessor::from_string("service", "service", LogValueType::String)),
+            Index::new(Accessor::from_string("user", "user", LogValueType::String)),
+        ];
+        let latency = Field::new(Accessor::from_string("latency", "latency", LogValueType::Second), OpType::Max);
+        let mut source = Table::new(TableDef::new(index, vec![latency], None, true));
+        source.aggregate(Box::new(Cursor::new(lines.join("\n"))));
+        let index = vec![Index::new(Accessor::from_string("service", "service", LogValueType::String))];
+        let latency = Field::new(Accessor::from_string("latency", "latency", LogValueType::Second), OpType::Min);
+        let mut table = Table::new(TableDef::new(index, vec![latency], None, true));
+        table.aggregate_records(source.records());
+        let values: Vec<Vec<LogValue>> = table.output_rows().into_iter().map(|(_, _, v)| v).collect();
+        assert_eq!(values, vec![vec![LogValue::Second(1.5)]]);
+    }
+
+    #[test]
+    fn check_limit_per_group() {
+        let lines = [
+            r#"{"service": "web", "status": "200"}"#,
+            r#"{"service": "web", "status": "500"}"#,
+            r#"{"service": "web", "status": "500"}"#,
+            r#"{"service": "api", "status": "200"}"#,
+            r#"{"service": "api", "status": "200"}"#,
+            r#"{"service": "api", "status": "200"}"#,
+            r#"{"service": "api", "status": "404"}"#,
+            r#"{"service": "api", "status": "404"}"#,
+            r#"{"service": "api", "status": "500"}"#,
+        ];
+        let mut table = build_table(&lines, false);
+        table.definition.limit_per_group = Some(1);
+        let rows: Vec<(RowKey, Vec<LogValue>)> = table.output_rows().into_iter()
+            .map(|(_, key, values)| (key, values))
+            .collect();
+        // Groups are in the order of services, each with the most frequent status.
+        assert_eq!(rows, vec![
+            (vec!["api".to_string(), "200".to_string()], vec![LogValue::Integer(3)]),
+            (vec!["web".to_string(), "500".to_string()], vec![LogValue::Integer(2)]),
+        ]);
+
+        let mut table = build_table(&lines, true);
+        table.definition.limit_per_group = Some(2);
+        let rows: Vec<(RowKind, RowKey)> = table.sorted_rows().into_iter()
+            .map(|(kind, key, _)| (kind, key))
+            .collect();
+        assert_eq!(rows, vec![
+            (RowKind::Subtotal, vec!["api".to_string()]),
+            (RowKind::Group, vec!["api".to_string(), "200".to_string()]),
+            (RowKind::Group, vec!["api".to_string(), "404".to_string()]),
+            (RowKind::Subtotal, vec!["web".to_string()]),
+            (RowKind::Group, vec!["web".to_string(), "500".to_string()]),
+            (RowKind::Group, vec!["web".to_string(), "200".to_string()]),
+            (RowKind::Total, vec![]),
+        ]);
+    }
+
+    #[test]
+    fn check_sort_with_none() {
+        // Standard deviation of a single value is empty.
+        let mut lines: Vec<String> = vec![];
+        for i in 0..30 {
+            lines.push(format!(r#"{{"service": "s{}", "latency": {}}}"#, i, i));
+            if i % 3 != 0 {
+                lines.push(format!(r#"{{"service": "s{}", "latency": {}}}"#, i, i * 2));
+            }
+        }
+        for ascending in [true, false] {
+            let index = vec![Index::new(Accessor::from_string("service", "service", LogValueType::String))];
+            let sd = Field::new(Accessor::from_string("sd", "latency", LogValueType::Float), OpType::StdDev);
+            let mut table = Table::new(TableDef::new(index, vec![sd.clone()], Some(OrderBy::Field(sd)), ascending));
+            table.aggregate(Box::new(Cursor::new(lines.join("\n"))));
+            let values: Vec<LogValue> = table.output_rows().into_iter().map(|(_, _, v)| v[0].clone()).collect();
+            assert_eq!(values.len(), 30);
+            // Empty values are the greatest.
+            let empty = values.iter().filter(|v| matches!(v, LogValue::None)).count();
+            assert_eq!(empty, 10);
+            let numbers: Vec<f64> = values.iter().filter_map(|v| v.as_f64()).collect();
+            if ascending {
+                assert!(values[20..].iter().all(|v| matches!(v, LogValue::None)));
+                assert!(numbers.windows(2).all(|w| w[0] <= w[1]));
+            } else {
+                assert!(values[..10].iter().all(|v| matches!(v, LogValue::None)));
+                assert!(numbers.windows(2).all(|w| w[0] >= w[1]));
+            }
+        }
+    }
+
+    #[test]
+    fn check_sort_time_buckets() {
+        // Clocks go back from 03:00 +02:00 to 02:00 +01:00 in Berlin.
+        let index = vec![Index::new(Accessor::from_string("time", "time", LogValueType::String))
+            .with_bucket(TimeBucket::parse("30m", Some("Europe/Berlin")).unwrap())];
+        let count = Field::new(Accessor::from_string("count", "time", LogValueType::String), OpType::Count);
+        let mut table = Table::new(TableDef::new(index, vec![count], None, true));
+        let lines = [
+            r#"{"time": "2022-10-30T01:40:00Z"}"#,
+            r#"{"time": "2022-10-30T00:40:00Z"}"#,
+            r#"{"time": "2022-10-30T01:10:00Z"}"#,
+        ];
+        table.aggregate(Box::new(Cursor::new(lines.join("\n"))));
+        let keys: Vec<RowKey> = table.sorted_rows().into_iter().map(|(_, key, _)| key).collect();
+        assert_eq!(keys, vec![
+            vec!["2022-10-30T02:30:00+02:00"], vec!["2022-10-30T02:00:00+01:00"], vec!["2022-10-30T02:30:00+01:00"],
+        ]);
+    }
+
+    #[test]
+    fn check_natural_cmp() {
+        let mut values = vec!["v10", "v2", "v1.10", "v1.9", "a", "500", "404", "1000", "01", "1"];
+        values.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(values, vec!["01", "1", "404", "500", "1000", "a", "v1.9", "v1.10", "v2", "v10"]);
+    }
+
+    #[test]
+    fn check_sort_keys() {
+        let lines = [
+            r#"{"service": "api", "status": "200"}"#,
+            r#"{"service": "api", "status": "404"}"#,
+            r#"{"service": "web", "status": "200"}"#,
+            r#"{"service": "web", "status": "404"}"#,
+            r#"{"service": "web", "status": "404"}"#,
+            r#"{"service": "v10", "status": "200"}"#,
+            r#"{"service": "v2", "status": "200"}"#,
+        ];
+        let mut table = build_table(&lines, false);
+        let count = table.definition.fields[0].clone();
+        table.definition.order_by = vec![
+            SortKey { by: OrderBy::Index(1), ascending: false },
+            SortKey { by: OrderBy::Field(count), ascending: false },
+        ];
+        let keys: Vec<RowKey> = table.sorted_rows().into_iter().map(|(_, key, _)| key).collect();
+        // Ties of status and count are broken by service in natural order.
+        assert_eq!(keys, vec![
+            vec!["web", "404"], vec!["api", "404"],
+            vec!["api", "200"], vec!["v2", "200"], vec!["v10", "200"], vec!["web", "200"],
+        ]);
+    }
+
+    #[test]
+    fn check_having() {
+        let lines = [
+            r#"{"service": "api", "status": "200"}"#,
+            r#"{"service": "api", "status": "200"}"#,
+            r#"{"service": "api", "status": "500"}"#,
+            r#"{"service": "web", "status": "404"}"#,
+        ];
+        let mut table = build_table(&lines, false);
+        table.definition.having = Some(Predicate::parse("count > 1 or status == 404").unwrap());
+        let keys: Vec<RowKey> = table.sorted_rows().into_iter()
+            .filter(|(kind, _, _)| *kind == RowKind::Group)
+            .map(|(_, key, _)| key)
+            .collect();
+        assert_eq!(keys, vec![vec!["api", "200"], vec!["web", "404"]]);
+
+        // With rollup, rows under a dropped subtotal are also dropped.
+        let mut table = build_table(&lines, true);
+        table.definition.having = Some(Predicate::parse("count >= 2").unwrap());
+        let keys: Vec<RowKey> = table.sorted_rows().into_iter()
+            .filter(|(kind, _, _)| *kind == RowKind::Group || *kind == RowKind::Subtotal)
+            .map(|(_, key, _)| key)
+            .collect();
+        assert_eq!(keys, vec![vec!["api"], vec!["api", "200"]]);
+    }
+}
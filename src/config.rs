@@ -1,30 +1,106 @@
 
 
 pub mod qma_config {
-    use serde::{ Serialize, Deserialize };
+    use serde::{ Serialize, Deserialize, Deserializer };
     use serde_yaml;
 
     #[derive(Serialize, Deserialize)]
     pub struct Config {
+        /// Name of the table, shown as a heading when several tables are printed.
+        pub name: Option<String>,
+        /// File to write the table to, instead of stdout.
+        pub output: Option<String>,
         pub output_format: Option<String>,
-        pub order_by: Option<String>,
+        /// One or list of field or index names, each optionally followed by `asc` or `desc`.
+        #[serde(default, deserialize_with = "one_or_many")]
+        pub order_by: Vec<String>,
         pub order: Option<String>,
-        pub index: Index,
-        pub fields: Vec<Field>
+        pub rollup: Option<bool>,
+        /// One index or list of indexes to group by.
+        #[serde(default, deserialize_with = "one_or_many")]
+        pub index: Vec<Index>,
+        /// Conditions which records must match to be aggregated.
+        #[serde(default, deserialize_with = "one_or_many")]
+        pub filter: Vec<String>,
+        /// Conditions which aggregated rows must match to be output.
+        #[serde(default, deserialize_with = "one_or_many")]
+        pub having: Vec<String>,
+        pub limit: Option<usize>,
+        /// Number of rows to output for each value of outer indexes.
+        pub limit_per_group: Option<usize>,
+        pub total: Option<bool>,
+        pub undefined: Option<Undefined>,
+        /// Index whose values are spread into columns.
+        pub columns: Option<Index>,
+        #[serde(default)]
+        pub fields: Vec<Field>,
+        /// Name of a preceding table whose rows are aggregated as records, instead of the input.
+        pub source: Option<String>,
+        /// Tables aggregated from the same input, instead of the table of this config.
+        #[serde(default)]
+        pub tables: Vec<Config>,
     }
 
     #[derive(Serialize, Deserialize)]
     pub struct Field {
         pub name: String,
+        // Not required by fields with `expression`.
+        #[serde(default)]
         pub accessor: String,
+        #[serde(default)]
         pub dtype: String,
+        #[serde(default)]
         pub operation: String,
+        /// Operations each of which becomes a column, instead of `operation`.
+        #[serde(default)]
+        pub operations: Vec<String>,
+        /// Expression over preceding fields, computed after aggregation.
+        pub expression: Option<String>,
+        pub percentile: Option<f64>,
+        pub exact: Option<bool>,
+        pub precision: Option<u8>,
+        pub k: Option<usize>,
+        pub time_accessor: Option<String>,
+        pub condition: Option<String>,
+        pub buckets: Option<Vec<f64>>,
+        pub threshold: Option<f64>,
+        pub target: Option<f64>,
+        pub weight: Option<String>,
+        pub percent: Option<bool>,
+        pub cumulative: Option<bool>,
     }
 
     #[derive(Serialize, Deserialize)]
     pub struct Index {
         pub name: String,
         pub accessor: String,
+        pub bucket: Option<String>,
+        pub timezone: Option<String>,
+        pub fill: Option<bool>,
+        pub from: Option<String>,
+        pub to: Option<String>,
+    }
+
+    /// Settings of the row for records without index values.
+    #[derive(Serialize, Deserialize)]
+    pub struct Undefined {
+        pub label: Option<String>,
+        /// `first`, `last` or `hidden`.
+        pub position: Option<String>,
+    }
+
+    fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where D: Deserializer<'de>, T: Deserialize<'de> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOrMany<T> {
+            One(T),
+            Many(Vec<T>),
+        }
+        match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(x) => Ok(vec![x]),
+            OneOrMany::Many(x) => Ok(x),
+        }
     }
 
     impl Config {
@@ -66,8 +142,8 @@ mod test {
             unreachable!();
         }
         // Check index.
-        assert_eq!(&config.index.name, "key");
-        assert_eq!(&config.index.accessor, "test.key");
+        assert_eq!(&config.index[0].name, "key");
+        assert_eq!(&config.index[0].accessor, "test.key");
         // Check field
 
         assert_eq!(&config.fields[0].name, "field1");
@@ -75,4 +151,81 @@ mod test {
         assert_eq!(&config.fields[0].dtype, "string");
         assert_eq!(&config.fields[0].operation, "OpCount");
     }
+
+    #[test]
+    fn check_config_operations() {
+        let s = "
+        index:
+            name: method
+            accessor: httpRequest.requestMethod
+        fields:
+           - name: latency
+             accessor: httpRequest.latency
+             dtype: second
+             operations: [count, average, p99, max]
+        ";
+
+        let config = Config::parse(s);
+        assert_eq!(config.fields[0].operations, vec!["count", "average", "p99", "max"]);
+        assert!(config.fields[0].operation.is_empty());
+    }
+
+    #[test]
+    fn check_config_tables() {
+        let s = "
+        output_format: json
+        tables:
+          - name: by_method
+            index:
+              name: method
+              accessor: httpRequest.requestMethod
+            fields:
+              - name: count
+                accessor: httpRequest.status
+                dtype: integer
+                operation: count
+          - name: by_status
+            source: by_method
+            output: status.csv
+            output_format: csv
+            index:
+              name: status
+              accessor: httpRequest.status
+        ";
+
+        let config = Config::parse(s);
+        assert!(config.index.is_empty());
+        assert_eq!(config.tables.len(), 2);
+        assert_eq!(config.tables[0].name.as_deref(), Some("by_method"));
+        assert!(config.tables[0].output.is_none());
+        assert!(config.tables[0].source.is_none());
+        assert_eq!(config.tables[1].source.as_deref(), Some("by_method"));
+        assert_eq!(config.tables[1].output.as_deref(), Some("status.csv"));
+        assert!(config.tables[1].fields.is_empty());
+    }
+
+    #[test]
+    fn check_config_multiple_index() {
+        let s = "
+        index:
+            - name: method
+              accessor: httpRequest.requestMethod
+            - name: status
+              accessor: httpRequest.status
+        fields:
+           - name: count
+             accessor: httpRequest.status
+             dtype: integer
+             operation: count
+        ";
+
+        let config = Config::parse(s);
+        assert_eq!(config.index.len(), 2);
+        assert_eq!(&config.index[0].name, "method");
+        assert_eq!(&config.index[1].accessor, "httpRequest.status");
+        assert!(config.columns.is_none());
+        assert!(config.filter.is_empty());
+        assert!(config.having.is_empty());
+        assert!(config.fields[0].expression.is_none());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,630 @@
+use crate::log_record::{ LogValue, LogValueType, parse_value };
+
+use regex::Regex;
+use std::cmp::{ Ordering };
+use std::error::Error;
+use std::io;
+
+/// Condition such as `status >= 500` or `method == "GET" and path =~ "^/api"`.
+///
+/// Identifiers are resolved by the caller, so the same predicate can be evaluated
+/// against json properties of a record.
+#[derive(Clone, Debug)]
+pub enum Predicate {
+    Compare { ident: String, op: CompareOp, value: Literal },
+    /// Matches if the value as string matches the regular expression.
+    Match { ident: String, regex: Regex },
+    /// Matches if the value equals to one of the values.
+    In { ident: String, values: Vec<Literal> },
+    /// Matches if the identifier has a value.
+    Exists(String),
+    Not(Box<Predicate>),
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+}
+
+/// Arithmetic expression over numbers such as `errors / count * 100`.
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Number(f64),
+    Ident(String),
+    Neg(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Literal {
+    String(String),
+    Number(f64),
+    Second(f64),
+}
+
+impl Predicate {
+    pub fn parse(s: &str) -> Result<Self, Box<dyn Error>> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let predicate = parser.or()?;
+        if parser.pos != parser.tokens.len() {
+            return Err(invalid(&format!("Unexpected token in condition: {}", s)));
+        }
+        Ok(predicate)
+    }
+
+    /// Evaluate the predicate. `resolve` returns the value of an identifier.
+    pub fn eval(&self, resolve: &dyn Fn(&str) -> LogValue) -> bool {
+        match self {
+            Predicate::Compare { ident, op, value } => {
+                let v = resolve(ident);
+                match compare(&v, value) {
+                    Some(ord) => op.matches(ord),
+                    // Missing or incomparable values only satisfy `!=`.
+                    None => *op == CompareOp::Ne,
+                }
+            },
+            Predicate::Match { ident, regex } => {
+                match resolve(ident) {
+                    LogValue::None => false,
+                    v => regex.is_match(&as_text(&v)),
+                }
+            },
+            Predicate::In { ident, values } => {
+                let v = resolve(ident);
+                values.iter().any(|x| compare(&v, x) == Some(Ordering::Equal))
+            },
+            Predicate::Exists(ident) => !matches!(resolve(ident), LogValue::None),
+            Predicate::Not(p) => !p.eval(resolve),
+            Predicate::And(a, b) => a.eval(resolve) && b.eval(resolve),
+            Predicate::Or(a, b) => a.eval(resolve) || b.eval(resolve),
+        }
+    }
+
+    /// Predicate matching only if both predicates match.
+    pub fn and(self, other: Predicate) -> Predicate {
+        Predicate::And(Box::new(self), Box::new(other))
+    }
+}
+
+impl Expr {
+    pub fn parse(s: &str) -> Result<Self, Box<dyn Error>> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.expr()?;
+        if parser.pos != parser.tokens.len() {
+            return Err(invalid(&format!("Unexpected token in expression: {}", s)));
+        }
+        Ok(expr)
+    }
+
+    /// Evaluate the expression. `None` if some of values are not numbers, or divided by zero.
+    pub fn eval(&self, resolve: &dyn Fn(&str) -> LogValue) -> Option<f64> {
+        match self {
+            Expr::Number(x) => Some(*x),
+            Expr::Ident(ident) => as_number(&resolve(ident)),
+            Expr::Neg(x) => Some(-x.eval(resolve)?),
+            Expr::Binary(op, x, y) => {
+                let (x, y) = (x.eval(resolve)?, y.eval(resolve)?);
+                match op {
+                    '+' => Some(x + y),
+                    '-' => Some(x - y),
+                    '*' => Some(x * y),
+                    _ if y == 0. => None,
+                    _ => Some(x / y),
+                }
+            }
+        }
+    }
+
+    /// Identifiers referred in the expression.
+    pub fn idents(&self) -> Vec<&str> {
+        match self {
+            Expr::Number(_) => vec![],
+            Expr::Ident(ident) => vec![ident.as_str()],
+            Expr::Neg(x) => x.idents(),
+            Expr::Binary(_, x, y) => {
+                let mut result = x.idents();
+                result.extend(y.idents());
+                result
+            }
+        }
+    }
+}
+
+impl CompareOp {
+    fn matches(&self, ord: Ordering) -> bool {
+        match self {
+            CompareOp::Eq => ord == Ordering::Equal,
+            CompareOp::Ne => ord != Ordering::Equal,
+            CompareOp::Lt => ord == Ordering::Less,
+            CompareOp::Le => ord != Ordering::Greater,
+            CompareOp::Gt => ord == Ordering::Greater,
+            CompareOp::Ge => ord != Ordering::Less,
+        }
+    }
+}
+
+/// Compare a value with a literal. Numbers are compared numerically, others as string as written in the log.
+fn compare(v: &LogValue, literal: &Literal) -> Option<Ordering> {
+    match literal {
+        Literal::Number(y) | Literal::Second(y) => {
+            let x = as_number(v)?;
+            x.partial_cmp(y)
+        },
+        Literal::String(y) => {
+            match v {
+                LogValue::None => None,
+                _ => Some(as_text(v).as_str().cmp(y.as_str())),
+            }
+        }
+    }
+}
+
+/// Value as written in the log. Whole numbers have no fraction digits.
+fn as_text(v: &LogValue) -> String {
+    match v {
+        LogValue::Float(x) => x.to_string(),
+        _ => v.as_string(),
+    }
+}
+
+fn as_number(v: &LogValue) -> Option<f64> {
+    match v {
+        LogValue::Integer(x) => Some(*x as f64),
+        LogValue::Float(x) => Some(*x),
+        LogValue::Second(x) => Some(*x),
+        LogValue::String(s) => {
+            if let Ok(x) = s.parse::<f64>() {
+                return Some(x);
+            }
+            match parse_value(&LogValueType::Second, s) {
+                LogValue::Second(x) => Some(x),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+fn invalid(msg: &str) -> Box<dyn Error> {
+    Box::new(io::Error::new(io::ErrorKind::InvalidInput, msg.to_string()))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Number(f64),
+    Second(f64),
+    Op(CompareOp),
+    /// One of `+`, `-`, `*` and `/`.
+    Arith(char),
+    /// `=~` or `!~`. True when negated.
+    Match(bool),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+    Comma,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, Box<dyn Error>> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens: Vec<Token> = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '"' || c == '\'' {
+            // Quoted string. Backslash escapes the quote and itself, and is kept otherwise
+            // so that regular expressions such as `\d` can be written as is.
+            let mut value = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    Some('\\') => {
+                        match chars.get(i + 1) {
+                            Some(x) if *x == c || *x == '\\' => value.push(*x),
+                            Some(x) => {
+                                value.push('\\');
+                                value.push(*x);
+                            },
+                            None => {}
+                        }
+                        i += 2;
+                    },
+                    Some(x) if *x == c => {
+                        i += 1;
+                        break;
+                    },
+                    Some(x) => {
+                        value.push(*x);
+                        i += 1;
+                    },
+                    None => {
+                        return Err(invalid(&format!("Unterminated string in condition: {}", s)));
+                    }
+                }
+            }
+            tokens.push(Token::Str(value));
+        } else if "+*/".contains(c) || (c == '-' && (follows_value(&tokens)
+            || !chars.get(i + 1).is_some_and(|x| x.is_ascii_digit()))) {
+            tokens.push(Token::Arith(c));
+            i += 1;
+        } else if "()&|,".contains(c) {
+            let two: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let (token, len) = match (c, two.as_str()) {
+                ('(', _) => (Token::LParen, 1),
+                (')', _) => (Token::RParen, 1),
+                (',', _) => (Token::Comma, 1),
+                (_, "&&") => (Token::And, 2),
+                (_, "||") => (Token::Or, 2),
+                _ => {
+                    return Err(invalid(&format!("Unexpected character '{}' in condition: {}", c, s)));
+                }
+            };
+            tokens.push(token);
+            i += len;
+        } else if "=!<>".contains(c) {
+            let two: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            match two.as_str() {
+                "=~" | "!~" => {
+                    tokens.push(Token::Match(c == '!'));
+                    i += 2;
+                    continue;
+                },
+                _ if c == '!' && two != "!=" => {
+                    tokens.push(Token::Not);
+                    i += 1;
+                    continue;
+                },
+                _ => {}
+            }
+            let (op, len) = match two.as_str() {
+                "==" => (CompareOp::Eq, 2),
+                "!=" => (CompareOp::Ne, 2),
+                "<=" => (CompareOp::Le, 2),
+                ">=" => (CompareOp::Ge, 2),
+                _ => match c {
+                    '=' => (CompareOp::Eq, 1),
+                    '<' => (CompareOp::Lt, 1),
+                    _ => (CompareOp::Gt, 1),
+                }
+            };
+            tokens.push(Token::Op(op));
+            i += len;
+        } else if c.is_ascii_digit() || (c == '-' && chars.get(i + 1).is_some_and(|x| x.is_ascii_digit())) {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let num: String = chars[start..i].iter().collect();
+            let num = num.parse::<f64>()
+                .map_err(|_| invalid(&format!("Invalid number in condition: {}", s)))?;
+            // Numbers with `s` or `sec` suffix are seconds.
+            let rest: String = chars[i..].iter().collect();
+            if rest.starts_with("sec") {
+                tokens.push(Token::Second(num));
+                i += 3;
+            } else if rest.starts_with('s') {
+                tokens.push(Token::Second(num));
+                i += 1;
+            } else {
+                tokens.push(Token::Number(num));
+            }
+        } else if is_ident_char(c) {
+            let start = i;
+            while i < chars.len() && is_ident_char(chars[i]) {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            // Keywords are case insensitive.
+            let token = match word.to_lowercase().as_str() {
+                "and" => Token::And,
+                "or" => Token::Or,
+                "not" => Token::Not,
+                _ => Token::Ident(word),
+            };
+            tokens.push(token);
+        } else {
+            return Err(invalid(&format!("Unexpected character '{}' in condition: {}", c, s)));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Names containing other characters, such as `-`, must be quoted.
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || "_.@$".contains(c)
+}
+
+/// Whether the last token ends a value, so that `-` after it is a subtraction rather than a sign.
+fn follows_value(tokens: &[Token]) -> bool {
+    matches!(tokens.last(),
+        Some(Token::Ident(_)) | Some(Token::Str(_)) | Some(Token::Number(_)) | Some(Token::Second(_)) | Some(Token::RParen))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    // expr := term (("+" | "-") term)*
+    fn expr(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut expr = self.term()?;
+        while let Some(Token::Arith(op)) = self.peek().cloned() {
+            if op != '+' && op != '-' {
+                break;
+            }
+            self.pos += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.term()?));
+        }
+        Ok(expr)
+    }
+
+    // term := factor (("*" | "/") factor)*
+    fn term(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut expr = self.factor()?;
+        while let Some(Token::Arith(op)) = self.peek().cloned() {
+            if op != '*' && op != '/' {
+                break;
+            }
+            self.pos += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.factor()?));
+        }
+        Ok(expr)
+    }
+
+    // factor := "-" factor | "(" expr ")" | number | ident
+    fn factor(&mut self) -> Result<Expr, Box<dyn Error>> {
+        match self.next() {
+            Some(Token::Arith('-')) => Ok(Expr::Neg(Box::new(self.factor()?))),
+            Some(Token::LParen) => {
+                let expr = self.expr()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            },
+            Some(Token::Number(x)) | Some(Token::Second(x)) => Ok(Expr::Number(x)),
+            // Quoted names may contain any characters.
+            Some(Token::Ident(x)) | Some(Token::Str(x)) => Ok(Expr::Ident(x)),
+            _ => Err(invalid("Value is expected in expression")),
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    // or := and (("or" | "||") and)*
+    fn or(&mut self) -> Result<Predicate, Box<dyn Error>> {
+        let mut predicate = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            predicate = Predicate::Or(Box::new(predicate), Box::new(self.and()?));
+        }
+        Ok(predicate)
+    }
+
+    // and := unary (("and" | "&&") unary)*
+    fn and(&mut self) -> Result<Predicate, Box<dyn Error>> {
+        let mut predicate = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            predicate = predicate.and(self.unary()?);
+        }
+        Ok(predicate)
+    }
+
+    // unary := ("not" | "!") unary | "(" or ")" | "exists" "(" ident ")" | comparison
+    fn unary(&mut self) -> Result<Predicate, Box<dyn Error>> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.pos += 1;
+                Ok(Predicate::Not(Box::new(self.unary()?)))
+            },
+            Some(Token::LParen) => {
+                self.pos += 1;
+                let predicate = self.or()?;
+                self.expect(Token::RParen)?;
+                Ok(predicate)
+            },
+            Some(Token::Ident(x)) if x == "exists" && self.tokens.get(self.pos + 1) == Some(&Token::LParen) => {
+                self.pos += 2;
+                let ident = self.ident()?;
+                self.expect(Token::RParen)?;
+                Ok(Predicate::Exists(ident))
+            },
+            _ => self.comparison(),
+        }
+    }
+
+    // comparison := ident op literal | ident ("=~" | "!~") string | ident ["not"] "in" "(" literal ("," literal)* ")"
+    fn comparison(&mut self) -> Result<Predicate, Box<dyn Error>> {
+        let ident = self.ident()?;
+        match self.next() {
+            Some(Token::Op(op)) => {
+                let value = self.literal()?;
+                Ok(Predicate::Compare { ident, op, value })
+            },
+            Some(Token::Match(negated)) => {
+                let pattern = match self.next() {
+                    Some(Token::Str(x)) | Some(Token::Ident(x)) => x,
+                    _ => { return Err(invalid("Regular expression is expected in condition")); }
+                };
+                let regex = Regex::new(&pattern)
+                    .map_err(|e| invalid(&format!("Invalid regular expression in condition: {}", e)))?;
+                let predicate = Predicate::Match { ident, regex };
+                Ok(if negated { Predicate::Not(Box::new(predicate)) } else { predicate })
+            },
+            Some(Token::Not) => {
+                self.keyword("in")?;
+                Ok(Predicate::Not(Box::new(self.in_list(ident)?)))
+            },
+            Some(Token::Ident(x)) if x.to_lowercase() == "in" => self.in_list(ident),
+            _ => Err(invalid("Comparison operator is expected in condition")),
+        }
+    }
+
+    fn in_list(&mut self, ident: String) -> Result<Predicate, Box<dyn Error>> {
+        self.expect(Token::LParen)?;
+        let mut values = vec![self.literal()?];
+        while self.peek() == Some(&Token::Comma) {
+            self.pos += 1;
+            values.push(self.literal()?);
+        }
+        self.expect(Token::RParen)?;
+        Ok(Predicate::In { ident, values })
+    }
+
+    fn ident(&mut self) -> Result<String, Box<dyn Error>> {
+        match self.next() {
+            // Quoted accessors may contain any characters.
+            Some(Token::Ident(x)) | Some(Token::Str(x)) => Ok(x),
+            _ => Err(invalid("Accessor is expected in condition")),
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str) -> Result<(), Box<dyn Error>> {
+        match self.next() {
+            Some(Token::Ident(x)) if x.to_lowercase() == keyword => Ok(()),
+            _ => Err(invalid(&format!("'{}' is expected in condition", keyword))),
+        }
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), Box<dyn Error>> {
+        if self.next() == Some(token) {
+            Ok(())
+        } else {
+            Err(invalid("Unbalanced parentheses in condition"))
+        }
+    }
+
+    fn literal(&mut self) -> Result<Literal, Box<dyn Error>> {
+        match self.next() {
+            Some(Token::Str(x)) => Ok(Literal::String(x)),
+            // Bare words are treated as strings.
+            Some(Token::Ident(x)) => Ok(Literal::String(x)),
+            Some(Token::Number(x)) => Ok(Literal::Number(x)),
+            Some(Token::Second(x)) => Ok(Literal::Second(x)),
+            _ => Err(invalid("Value is expected in condition")),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(s: &str, status: LogValue) -> bool {
+        let predicate = Predicate::parse(s).unwrap();
+        predicate.eval(&|ident: &str| {
+            match ident {
+                "status" => status.clone(),
+                "method" => LogValue::String(String::from("GET")),
+                "latency" => LogValue::String(String::from("1.5s")),
+                _ => LogValue::None,
+            }
+        })
+    }
+
+    #[test]
+    fn check_predicate() {
+        assert!(eval("status >= 500", LogValue::Integer(503)));
+        assert!(!eval("status >= 500", LogValue::Integer(200)));
+        assert!(eval("status == 200", LogValue::String(String::from("200"))));
+        assert!(eval("status<300", LogValue::Float(200.)));
+        // Numbers in json are compared with quoted literals as written in the log.
+        assert!(eval("status == '200'", LogValue::Float(200.)));
+        assert!(eval("status in ('200', '404')", LogValue::Float(404.)));
+        assert!(eval("status == '0.5'", LogValue::Float(0.5)));
+        assert!(eval("status != 200", LogValue::None));
+        assert!(!eval("status == 200", LogValue::None));
+        assert!(eval("method == GET", LogValue::None));
+        assert!(eval("method = 'GET'", LogValue::None));
+        assert!(!eval("method != \"GET\"", LogValue::None));
+        assert!(eval("latency > 1.0s", LogValue::None));
+        assert!(!eval("unknown > 0", LogValue::None));
+    }
+
+    #[test]
+    fn check_predicate_combinators() {
+        assert!(eval("method == GET and status >= 500", LogValue::Integer(503)));
+        assert!(!eval("method == GET && status >= 500", LogValue::Integer(200)));
+        assert!(eval("method == POST or status < 300", LogValue::Integer(200)));
+        assert!(eval("not (method == POST || status >= 500)", LogValue::Integer(200)));
+        assert!(eval("!exists(unknown) and exists(method)", LogValue::None));
+        assert!(eval("status in (200, 204)", LogValue::Float(204.)));
+        assert!(eval("status > -1", LogValue::Float(0.)));
+        assert!(eval("'status' in (-1, 0)", LogValue::Float(-1.)));
+        assert!(eval("method not in (POST, 'PUT')", LogValue::None));
+        assert!(eval("status =~ '^2\\d\\d$'", LogValue::Float(200.)));
+        assert!(eval("method !~ \"^P\"", LogValue::None));
+        assert!(!eval("unknown =~ '.*'", LogValue::None));
+        // `and` binds tighter than `or`.
+        assert!(eval("method == GET or method == POST and status == 1", LogValue::Integer(200)));
+    }
+
+    #[test]
+    fn check_expr() {
+        let resolve = |ident: &str| match ident {
+            "errors" => LogValue::Integer(5),
+            "count" => LogValue::Integer(20),
+            "latency" => LogValue::Second(0.5),
+            "zero" => LogValue::Float(0.),
+            "error-rate" => LogValue::Float(0.25),
+            _ => LogValue::None,
+        };
+        let eval = |s: &str| Expr::parse(s).unwrap().eval(&resolve);
+        assert_eq!(eval("errors / count"), Some(0.25));
+        assert_eq!(eval("errors/count*100"), Some(25.));
+        assert_eq!(eval("count - errors * 2"), Some(10.));
+        assert_eq!(eval("(count - errors) * 2"), Some(30.));
+        assert_eq!(eval("-latency + 1"), Some(0.5));
+        assert_eq!(eval("count / zero"), None);
+        // `-` is always an operator after a value, and names containing it are quoted.
+        assert_eq!(eval("count-errors"), Some(15.));
+        assert_eq!(eval("count - errors"), Some(15.));
+        assert_eq!(eval("count -1"), Some(19.));
+        assert_eq!(eval("count * -1"), Some(-20.));
+        assert_eq!(eval("'error-rate' * 100"), Some(25.));
+        assert_eq!(Expr::parse("error-rate").unwrap().idents(), vec!["error", "rate"]);
+        assert_eq!(eval("unknown + 1"), None);
+        assert_eq!(Expr::parse("errors / count").unwrap().idents(), vec!["errors", "count"]);
+
+        assert!(Expr::parse("errors /").is_err());
+        assert!(Expr::parse("(errors + 1").is_err());
+        assert!(Expr::parse("errors count").is_err());
+    }
+
+    #[test]
+    fn check_predicate_error() {
+        assert!(Predicate::parse("status >=").is_err());
+        assert!(Predicate::parse(">= 500").is_err());
+        assert!(Predicate::parse("status 500").is_err());
+        assert!(Predicate::parse("status == \"500").is_err());
+        assert!(Predicate::parse("status == 500 500").is_err());
+        assert!(Predicate::parse("(status == 500").is_err());
+        assert!(Predicate::parse("status in 500").is_err());
+        assert!(Predicate::parse("status =~ '('").is_err());
+        assert!(Predicate::parse("status == 500 and").is_err());
+    }
+}
@@ -1,37 +1,40 @@
 
 mod aggregate;
 mod config;
+mod expression;
 mod log_record;
 mod operation;
+mod sketch;
+mod time_bucket;
 mod visualize;
 
 use std::str;
 use std::io;
 use std::fs::File;
-use std::io::{ BufRead, BufReader };
+use std::io::{ BufRead, BufReader, BufWriter, Write };
 use std::error::Error;
 use std::process;
 
-pub use crate::aggregate::{ Table, TableDef, Index, Field };
+pub use crate::aggregate::{ Table, TableDef, Index, Field, OrderBy, RowPosition, SortKey };
 pub use crate::log_record::{ LogValueType, LogValue, Accessor };
 pub use crate::config::qma_config:: { Config };
-pub use crate::operation::{ OpType };
+pub use crate::expression::{ Expr, Predicate };
+pub use crate::operation::{ Objective, OpType };
+pub use crate::sketch::{ HyperLogLog, QuantileSketch, SpaceSaving };
+pub use crate::time_bucket::{ GapFill, TimeBucket };
 pub use crate::visualize::{ VisualizeType };
 
+/// Number of values reported by `top_k` when `k` is not specified.
+const DEFAULT_TOP_K: usize = 3;
 
 pub fn run(config_path: &str, filename: Option<&str>) {
     // initialize config
     let config = load_config(config_path).expect("Failed to load config file");
-    let output_format = match &config.output_format {
-        Some(f) => {
-            // let f = str::to_lowercase(f);
-            match f.as_str() {
-                "csv"=> VisualizeType::Csv,
-                "markdown"=> VisualizeType::Markdown,
-                _ => VisualizeType::Markdown
-            }
-        },
-        None => VisualizeType::Markdown
+    // Without `tables`, the config itself describes the only table.
+    let configs: Vec<&Config> = if config.tables.is_empty() {
+        vec![&config]
+    } else {
+        config.tables.iter().collect()
     };
 
     // initialize reader.
@@ -50,30 +53,106 @@ pub fn run(config_path: &str, filename: Option<&str>) {
     };
 
 
-    let result = build_table_def(&config);
-    if let Ok(def) = result {
-        // let mut table: HashMap<String, TableRow> = HashMap::new();
-        let mut table = Table::new(def);
-        table.aggregate(reader);
-
-        match output_format {
-            VisualizeType::Csv => {
-                visualize::display_as_csv(&mut table);
+    let mut tables: Vec<Table> = vec![];
+    let mut sources: Vec<Option<usize>> = vec![];
+    for (i, c) in configs.iter().enumerate() {
+        let result = build_table_def(c)
+            .and_then(|def| Ok((def, find_source(c, &configs[..i])?)));
+        match result {
+            Ok((def, source)) => {
+                tables.push(Table::new(def));
+                sources.push(source);
             },
-            VisualizeType::Markdown => {
-                visualize::display_as_markdown(&mut table);
+            Err(e) => {
+                eprintln!("Error: failed to parse config file. {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    // Tables with `source` aggregate the rows of their source after the input is read.
+    let mut inputs: Vec<&mut Table> = tables.iter_mut().zip(sources.iter())
+        .filter(|(_, s)| s.is_none())
+        .map(|(t, _)| t)
+        .collect();
+    aggregate::aggregate_tables(&mut inputs, reader);
+    for (i, source) in sources.iter().enumerate() {
+        if let Some(s) = source {
+            let records = tables[*s].records();
+            tables[i].aggregate_records(records);
+        }
+    }
+
+    let mut printed = 0;
+    for (c, table) in configs.iter().zip(tables.iter_mut()) {
+        // Tables inherit the output format of the whole config.
+        let output_format = visualize_type(c.output_format.as_ref().or(config.output_format.as_ref()));
+        let result = match &c.output {
+            Some(path) => File::create(path).and_then(|f| {
+                let mut out = BufWriter::new(f);
+                display(table, &output_format, &mut out)?;
+                out.flush()
+            }),
+            None => {
+                let stdout = io::stdout();
+                let mut out = stdout.lock();
+                let mut result = Ok(());
+                // Separate tables printed one after another.
+                if printed > 0 {
+                    result = writeln!(out);
+                }
+                if let (Some(name), VisualizeType::Markdown, true) = (&c.name, &output_format, configs.len() > 1) {
+                    result = result.and_then(|_| writeln!(out, "## {}\n", name));
+                }
+                printed += 1;
+                result.and_then(|_| display(table, &output_format, &mut out))
             }
         };
-    } else {
-        eprintln!("Error: failed to parse config file.");
-        process::exit(1);
+        if let Err(e) = result {
+            // Stop quietly when the reader of stdout is gone, such as `head`.
+            if e.kind() == io::ErrorKind::BrokenPipe {
+                return;
+            }
+            eprintln!("Error: failed to write output. {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn visualize_type(output_format: Option<&String>) -> VisualizeType {
+    match output_format.map(|f| f.as_str()) {
+        Some("csv") => VisualizeType::Csv,
+        Some("json") => VisualizeType::Json,
+        _ => VisualizeType::Markdown,
+    }
+}
+
+fn display(table: &mut Table, output_format: &VisualizeType, out: &mut dyn Write) -> io::Result<()> {
+    match output_format {
+        VisualizeType::Csv => visualize::display_as_csv(table, out),
+        VisualizeType::Markdown => visualize::display_as_markdown(table, out),
+        VisualizeType::Json => visualize::display_as_json(table, out),
     }
 }
 
 fn build_table_def(config: &Config) -> Result<TableDef, Box<dyn Error>> {
     // build index.
-    let index = Index::new(Accessor::from_string(
-        &config.index.name, &config.index.accessor, LogValueType::String));
+    if config.index.is_empty() {
+        return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "index is required")));
+    }
+    let mut index :Vec<Index> = vec![];
+    for qma_index in config.index.iter() {
+        index.push(build_index(qma_index)?);
+    }
+    let columns = match &config.columns {
+        Some(c) => {
+            if c.fill.is_some() {
+                return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "fill is not supported by columns")));
+            }
+            Some(build_index(c)?)
+        },
+        None => None,
+    };
 
     // build fields
     let mut fields :Vec<Field> = vec![];
@@ -83,31 +162,51 @@ fn build_table_def(config: &Config) -> Result<TableDef, Box<dyn Error>> {
             "integer" => LogValueType::Integer,
             "float" => LogValueType::Float,
             "second" => LogValueType::Second,
+            "timestamp" => LogValueType::Timestamp,
             _ => LogValueType::None
         };
-        let accessor = Accessor::from_string(&qma_field.name, &qma_field.accessor, dtype);
 
-        let op_type = match qma_field.operation.as_str() {
-            "average" => OpType::Average,
-            "count" => OpType::Count,
-            _ => OpType::Average
+        // Pairs of column name and operation. Each of `operations` becomes a column named `<name>_<operation>`.
+        let operations: Vec<(String, &str)> = if qma_field.operations.is_empty() {
+            vec![(qma_field.name.clone(), qma_field.operation.as_str())]
+        } else if qma_field.operation.is_empty() && qma_field.expression.is_none() {
+            qma_field.operations.iter()
+                .map(|op| (format!("{}_{}", qma_field.name, op), op.as_str()))
+                .collect()
+        } else {
+            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput,
+                "operations can not be used with operation or expression")));
         };
-        fields.push(Field::new(accessor, op_type));
-    }
-
-    // find order by field.
-    let mut order_by: Option<Field> = None;
-    if let Some(o) = &config.order_by {
-        let mut ok = false;
-        for field in fields.iter() {
-            if &field.accessor.name == o {
-                order_by = Some(field.clone());
-                ok = true;
-                break;
+
+        for (name, operation) in operations {
+            let accessor = Accessor::from_string(&name, &qma_field.accessor, dtype.clone());
+            let op_type = match &qma_field.expression {
+                Some(e) => {
+                    let expr = Expr::parse(e)?;
+                    // Referring only to preceding fields prevents cycles.
+                    if let Some(n) = expr.idents().into_iter().find(|n| !fields.iter().any(|f| f.name() == *n)) {
+                        return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput,
+                            format!("expression must refer to preceding fields: {}", n))));
+                    }
+                    OpType::Expression { expr }
+                },
+                None => build_op_type(qma_field, operation)?,
+            };
+            let mut field = Field::new(accessor, op_type);
+            if let Some(w) = &qma_field.weight {
+                if !field.op_type.supports_weight() {
+                    return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput,
+                        format!("weight is not supported by {}", operation))));
+                }
+                field = field.with_weight(Accessor::from_string(w, w, LogValueType::Float));
             }
-        }
-        if !ok {
-            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "Invalid order_by value")));
+            if qma_field.percent.unwrap_or(false) {
+                field = field.with_percent();
+            }
+            if qma_field.cumulative.unwrap_or(false) {
+                field = field.with_cumulative();
+            }
+            fields.push(field);
         }
     }
 
@@ -128,11 +227,280 @@ fn build_table_def(config: &Config) -> Result<TableDef, Box<dyn Error>> {
         }
 
     }
-    let table_def = TableDef::new(index, fields, order_by, ascending);
+
+    // find order by fields or indexes. Each may be followed by its own direction, like `count desc`.
+    let mut order_by: Vec<SortKey> = vec![];
+    for o in config.order_by.iter() {
+        let (name, direction) = match o.trim().rsplit_once(' ') {
+            Some((name, d)) if ["asc", "desc"].contains(&d.to_lowercase().as_str()) => (name.trim(), Some(d)),
+            _ => (o.trim(), None),
+        };
+        let by = if let Some(field) = fields.iter().find(|f| f.name() == name) {
+            OrderBy::Field(field.clone())
+        } else if let Some(i) = index.iter().position(|i| i.name() == name) {
+            OrderBy::Index(i)
+        } else {
+            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "Invalid order_by value")));
+        };
+        let ascending = direction.map_or(ascending, |d| d.to_lowercase() == "asc");
+        order_by.push(SortKey { by, ascending });
+    }
+
+    let mut table_def = TableDef::new(index, fields, None, ascending);
+    table_def.order_by = order_by;
+    table_def.rollup = config.rollup.unwrap_or(false);
+    table_def.columns = columns;
+    table_def.filter = build_conditions(&config.filter)?;
+    table_def.having = build_conditions(&config.having)?;
+    table_def.limit = config.limit;
+    if config.limit_per_group.is_some() && table_def.index.len() < 2 {
+        return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "limit_per_group requires two or more indexes")));
+    }
+    table_def.limit_per_group = config.limit_per_group;
+    table_def.total = config.total.unwrap_or(table_def.rollup);
+    if let Some(u) = &config.undefined {
+        if let Some(label) = &u.label {
+            table_def.undefined_label = label.clone();
+        }
+        if let Some(p) = &u.position {
+            table_def.undefined_position = match p.to_lowercase().as_str() {
+                "first" => RowPosition::First,
+                "last" => RowPosition::Last,
+                "hidden" => RowPosition::Hidden,
+                _ => {
+                    return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "Invalid undefined position value")));
+                }
+            };
+        }
+    }
     Ok(table_def)
 }
 
+/// Position of the table named by `source` of the config among preceding tables.
+/// Referring only to preceding tables prevents cycles.
+fn find_source(config: &Config, preceding: &[&Config]) -> Result<Option<usize>, Box<dyn Error>> {
+    match &config.source {
+        Some(s) => match preceding.iter().position(|c| c.name.as_ref() == Some(s)) {
+            Some(i) => Ok(Some(i)),
+            None => Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("source must be the name of a preceding table: {}", s)))),
+        },
+        None => Ok(None),
+    }
+}
+
+fn build_index(qma_index: &config::qma_config::Index) -> Result<Index, Box<dyn Error>> {
+    let mut i = Index::new(Accessor::from_string(
+        &qma_index.name, &qma_index.accessor, LogValueType::String));
+    if let Some(b) = &qma_index.bucket {
+        let bucket = TimeBucket::parse(b, qma_index.timezone.as_deref())?;
+        if qma_index.fill.unwrap_or(false) {
+            let fill = GapFill::parse(qma_index.from.as_deref(), qma_index.to.as_deref())?;
+            if fill.bucket_count(&bucket).is_some_and(|c| c > aggregate::MAX_FILLED_ROWS as i64) {
+                return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("fill range must have at most {} buckets", aggregate::MAX_FILLED_ROWS))));
+            }
+            i = i.with_fill(fill);
+        }
+        i = i.with_bucket(bucket);
+    } else if qma_index.fill.is_some() {
+        return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "fill requires bucket")));
+    }
+    Ok(i)
+}
+
+fn build_op_type(qma_field: &config::qma_config::Field, operation: &str) -> Result<OpType, Box<dyn Error>> {
+    let op_type = match operation {
+        "average" => OpType::Average,
+        "min" => OpType::Min,
+        "max" => OpType::Max,
+        "count" => OpType::Count,
+        "sum" => OpType::Sum,
+        "variance" => OpType::Variance,
+        "stddev" => OpType::StdDev,
+        "cv" => OpType::Cv,
+        "first" => OpType::First { time: build_time_accessor(qma_field) },
+        "last" => OpType::Last { time: build_time_accessor(qma_field) },
+        "count_if" => OpType::CountIf { condition: build_condition(qma_field)? },
+        "average_if" => OpType::AverageIf { condition: build_condition(qma_field)? },
+        "ratio" => OpType::Ratio { condition: build_condition(qma_field)? },
+        "histogram" => {
+            if let Some(b) = &qma_field.buckets {
+                if b.is_empty() || b.windows(2).any(|w| w[0] >= w[1]) {
+                    return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "buckets must be sorted in ascending order")));
+                }
+            }
+            OpType::Histogram { bounds: qma_field.buckets.clone() }
+        },
+        "apdex" => {
+            match qma_field.threshold {
+                Some(threshold) if threshold > 0. => OpType::Apdex { threshold },
+                _ => {
+                    return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "positive threshold is required")));
+                }
+            }
+        },
+        "slo" => OpType::Slo { objective: build_objective(qma_field)? },
+        "error_budget" => {
+            let target = match qma_field.target {
+                Some(target) if target > 0. && target < 100. => target / 100.,
+                _ => {
+                    return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "target between 0 and 100 is required")));
+                }
+            };
+            OpType::ErrorBudget { objective: build_objective(qma_field)?, target }
+        },
+        "first_seen" => OpType::FirstSeen,
+        "last_seen" => OpType::LastSeen,
+        "top_k" => {
+            let k = qma_field.k.unwrap_or(DEFAULT_TOP_K);
+            if !(1..=operation::MAX_TOP_K).contains(&k) {
+                return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("k must be between 1 and {}", operation::MAX_TOP_K))));
+            }
+            OpType::TopK { k }
+        },
+        "count_distinct" => {
+            let exact = qma_field.exact.unwrap_or(true);
+            let precision = qma_field.precision.unwrap_or(sketch::DEFAULT_HLL_PRECISION);
+            if !(HyperLogLog::MIN_PRECISION..=HyperLogLog::MAX_PRECISION).contains(&precision) {
+                return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "Invalid precision value")));
+            }
+            OpType::CountDistinct { exact, precision }
+        },
+        "percentile" => {
+            let rank = match qma_field.percentile {
+                Some(rank) => rank,
+                None => {
+                    return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "percentile value is required")));
+                }
+            };
+            OpType::Percentile { rank, exact: qma_field.exact.unwrap_or(false) }
+        },
+        // Shorthand such as p50, p99 or p99.9.
+        op if op.starts_with('p') && op[1..].parse::<f64>().is_ok() => {
+            let rank = op[1..].parse::<f64>()?;
+            OpType::Percentile { rank, exact: qma_field.exact.unwrap_or(false) }
+        },
+        "" => {
+            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "operation is required")));
+        },
+        op => {
+            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown operation: {}", op))));
+        }
+    };
+    if let OpType::Percentile { rank, .. } = op_type {
+        if !(0. ..=100.).contains(&rank) {
+            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "Invalid percentile value")));
+        }
+    }
+    Ok(op_type)
+}
+
+fn build_time_accessor(qma_field: &config::qma_config::Field) -> Option<Accessor> {
+    qma_field.time_accessor.as_ref()
+        .map(|a| Accessor::from_string(a, a, LogValueType::Timestamp))
+}
+
+fn build_condition(qma_field: &config::qma_config::Field) -> Result<Predicate, Box<dyn Error>> {
+    match &qma_field.condition {
+        Some(c) => Predicate::parse(c),
+        None => Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "condition is required"))),
+    }
+}
+
+/// Predicate matching if all of conditions match. `None` if there are no conditions.
+fn build_conditions(conditions: &[String]) -> Result<Option<Predicate>, Box<dyn Error>> {
+    let mut result: Option<Predicate> = None;
+    for c in conditions {
+        let p = Predicate::parse(c)?;
+        result = Some(match result {
+            Some(r) => r.and(p),
+            None => p,
+        });
+    }
+    Ok(result)
+}
+
+fn build_objective(qma_field: &config::qma_config::Field) -> Result<Objective, Box<dyn Error>> {
+    if qma_field.condition.is_none() && qma_field.threshold.is_none() {
+        return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "condition or threshold is required")));
+    }
+    let condition = match &qma_field.condition {
+        Some(_) => Some(build_condition(qma_field)?),
+        None => None,
+    };
+    Ok(Objective { condition, threshold: qma_field.threshold })
+}
+
 fn load_config(filepath: &str) -> Result<Config, Box<dyn Error>>{
     let contents = std::fs::read_to_string(filepath)?;
     Ok(Config::parse(&contents))
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_unknown_operation() {
+        let config = Config::parse("
+        index:
+          name: path
+          accessor: path
+        fields:
+          - name: lat
+            accessor: latency
+            dtype: second
+            operations: [average, stdev]
+        ");
+        let e = build_table_def(&config).err().unwrap();
+        assert_eq!(e.to_string(), "Unknown operation: stdev");
+
+        let config = Config::parse("
+        index:
+          name: path
+          accessor: path
+        fields:
+          - name: lat
+            accessor: latency
+            operations: [average, stddev, p99]
+        ");
+        let def = build_table_def(&config).unwrap();
+        assert_eq!(def.fields.len(), 3);
+    }
+
+    #[test]
+    fn check_top_k_range() {
+        for (k, ok) in [(0, false), (1, true), (10_000, true), (10_001, false), (usize::MAX, false)] {
+            let config = Config::parse(&format!("
+            index:
+              name: path
+              accessor: path
+            fields:
+              - name: agents
+                accessor: userAgent
+                dtype: string
+                operation: top_k
+                k: {}
+            ", k));
+            assert_eq!(build_table_def(&config).is_ok(), ok, "k: {}", k);
+        }
+    }
+
+    #[test]
+    fn check_fill_range() {
+        let config = Config::parse("
+        index:
+          name: second
+          accessor: time
+          bucket: 1s
+          fill: true
+          from: 2022-01-01T00:00:00Z
+          to: 2022-12-31T00:00:00Z
+        ");
+        let e = build_table_def(&config).err().unwrap();
+        assert_eq!(e.to_string(), "fill range must have at most 100000 buckets");
+    }
+}
@@ -3,7 +3,8 @@ use std::fmt;
 use std::io::{BufRead };
 use std::cmp::{ Ordering, PartialOrd, Ord, PartialEq, Eq };
 use std::collections::{ HashMap };
-use serde_json::{Result, Value, };
+use serde_json::{Result, Value, json };
+use chrono::{ DateTime, FixedOffset, SecondsFormat, TimeZone, Utc };
 
 #[derive(Clone)]
 pub struct Accessor {
@@ -28,28 +29,40 @@ impl Accessor {
 }
 
 pub struct LogRecord {
-    pub key: Option<String>,
-    pub values: HashMap<String, LogValue>
+    /// Values of indexes. `None` if some of them are missing.
+    pub key: Option<Vec<String>>,
+    pub values: HashMap<String, LogValue>,
+    /// Position of the record in the input stream.
+    pub position: u64,
+    /// Original json value, to look up properties other than fields.
+    pub raw: Value,
 }
 
 impl LogRecord {
     pub fn new (key: &str) -> Self {
         Self {
-            key: Some(String::from(key)),
-            values: HashMap::new()
+            key: Some(vec![String::from(key)]),
+            values: HashMap::new(),
+            position: 0,
+            raw: Value::Null,
         }
     }
 
-    pub fn parse(reader :&mut Box<dyn BufRead>, index: &Accessor, fields :&[&Accessor]) -> Result<LogRecord> {
-        // read line
+    /// Read one line of json from the reader.
+    pub fn read_json(reader :&mut Box<dyn BufRead>) -> Result<Value> {
         let mut buf = String::new();
         reader.read_line(&mut buf).expect("error");
-        let v: Value = serde_json::from_str(&buf)?;
+        serde_json::from_str(&buf)
+    }
 
+    /// Build the record with values of indexes and fields read from the json.
+    pub fn from_json(v: Value, index: &[&Accessor], fields :&[&Accessor]) -> LogRecord {
         // Read key and init log record.
-        let key = get_value(&v, &index.accessor, 0); 
+        let key: Option<Vec<String>> = index.iter()
+            .map(|i| get_value(&v, &i.accessor, 0))
+            .collect();
         let mut record = LogRecord {
-            key, values: HashMap::new()
+            key, values: HashMap::new(), position: 0, raw: Value::Null
         };
 
         // Read data
@@ -59,7 +72,8 @@ impl LogRecord {
                 record.set(f.name.as_str(), v, &f.dtype);
             }
         }
-        Ok(record)
+        record.raw = v;
+        record
     }
 
     pub fn set(&mut self, key: &str, value: String, typ: &LogValueType) {
@@ -75,6 +89,29 @@ impl LogRecord {
         }
     }
 
+    /// Read a property by dot separated path without type conversion.
+    /// Numbers are read as float and others as string.
+    pub fn resolve(&self, path: &str) -> LogValue {
+        let mut v = &self.raw;
+        for key in path.split('.') {
+            v = &v[key];
+        }
+        match v {
+            Value::String(s) => LogValue::String(s.clone()),
+            Value::Number(n) => n.as_f64().map_or(LogValue::None, LogValue::Float),
+            Value::Bool(b) => LogValue::String(b.to_string()),
+            _ => LogValue::None,
+        }
+    }
+
+    /// Read a property which is not necessarily a field of the table.
+    pub fn lookup(&self, accessor: &Accessor) -> LogValue {
+        match get_value(&self.raw, &accessor.accessor, 0) {
+            Some(v) => parse_value(&accessor.dtype, &v),
+            None => LogValue::None,
+        }
+    }
+
 }
 
 #[derive(Clone)]
@@ -83,6 +120,7 @@ pub enum LogValueType {
     Integer,
     Float,
     Second,
+    Timestamp,
     None,
 }
 
@@ -93,9 +131,33 @@ pub enum LogValue {
     Integer(u32),
     Float(f64),
     Second(f64),
+    Timestamp(DateTime<FixedOffset>),
+    /// Most frequent values with their counts.
+    TopK(Vec<(String, u64)>),
+    Histogram(Vec<Bucket>),
     None,
 }
 
+/// Histogram bucket which counts values in `[lower, upper)`.
+/// `None` means the bucket is unbounded on that side.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bucket {
+    pub lower: Option<f64>,
+    pub upper: Option<f64>,
+    pub count: u64,
+}
+
+impl Bucket {
+    pub fn label(&self) -> String {
+        match (self.lower, self.upper) {
+            (Some(l), Some(u)) => format!("{}-{}", l, u),
+            (Some(l), None) => format!(">={}", l),
+            (None, Some(u)) => format!("<{}", u),
+            (None, None) => String::from("*"),
+        }
+    }
+}
+
 impl fmt::Display for LogValue {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -103,29 +165,19 @@ impl fmt::Display for LogValue {
             LogValue::Integer(s) => write!(f, "Integer({})", s),
             LogValue::Float(s) => write!(f, "Float({})", s),
             LogValue::Second(s) => write!(f, "Second({}s)", s),
+            LogValue::Timestamp(s) => write!(f, "Timestamp({})", s.to_rfc3339()),
+            LogValue::TopK(_) => write!(f, "TopK({})", self.as_string()),
+            LogValue::Histogram(_) => write!(f, "Histogram({})", self.as_string()),
             _ => write!(f, "None")
         }
     }
 }
 
 
+/// Equal when `cmp` returns `Equal`, to keep `Eq` consistent with `Ord`.
 impl PartialEq for LogValue {
     fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Self::String(x), Self::String(y)) => {
-                x == y
-            }
-            (Self::Integer(x), Self::Integer(y)) => {
-                x == y
-            },
-            (Self::Float(x), Self::Float(y)) => {
-                (x - y) < 1e-10 
-            }
-            (Self::Second(x), Self::Second(y)) => {
-                (x - y) < 1e-10 
-            },
-            _ => false
-        }
+        self.cmp(other) == Ordering::Equal
     }
 }
 
@@ -147,27 +199,36 @@ impl Ord for LogValue {
             (Self::Integer(x), Self::Integer(y)) => {
                 x.cmp(y)
             },
-            (Self::Float(x), Self::Float(y)) => {
-                match x.partial_cmp(y) {
-                    Some(x) => x,
-                    None => Ordering::Equal
-                }
-            }
-            (Self::Second(x), Self::Second(y)) => {
-                match x.partial_cmp(y) {
-                    Some(x) => x,
-                    None => Ordering::Equal
-                }
+            (Self::Timestamp(x), Self::Timestamp(y)) => {
+                x.cmp(y)
+            },
+            (Self::TopK(x), Self::TopK(y)) => {
+                x.cmp(y)
+            },
+            // Histograms are compared bucket by bucket, only to be consistent with equality.
+            (Self::Histogram(x), Self::Histogram(y)) => {
+                let bound = |a: Option<f64>, b: Option<f64>| match (a, b) {
+                    (Some(a), Some(b)) => a.total_cmp(&b),
+                    (a, b) => a.is_some().cmp(&b.is_some()),
+                };
+                x.iter().zip(y.iter())
+                    .map(|(a, b)| bound(a.lower, b.lower)
+                        .then_with(|| bound(a.upper, b.upper))
+                        .then_with(|| a.count.cmp(&b.count)))
+                    .find(|o| *o != Ordering::Equal)
+                    .unwrap_or_else(|| x.len().cmp(&y.len()))
             },
             (Self::None, Self::None) => Ordering::Equal,
 
-            // Different type comparison.
-            (Self::String(_), _) => Ordering::Less,
-            (Self::Integer(_), _) => Ordering::Less,
-            (Self::Float(_), _) => Ordering::Less,
-            (Self::Second(_), _) => Ordering::Less,
-            (Self::None, _) => Ordering::Equal,
+            // Empty values are greater than any others, to keep the order total.
+            (Self::None, _) => Ordering::Greater,
+            (_, Self::None) => Ordering::Less,
 
+            // Numbers of different types are compared numerically, and others by type.
+            _ => match (self.as_f64(), other.as_f64()) {
+                (Some(x), Some(y)) => x.total_cmp(&y),
+                _ => self.type_rank().cmp(&other.type_rank()),
+            },
         }
     }
 }
@@ -182,29 +243,96 @@ impl LogValue {
                 x.to_string()
             },
             LogValue::Float(x) => {
-                format!("{:.4}", x.to_string())
+                format!("{:.4}", x)
             },
             LogValue::Second(x) => {
                 format!("{:.4}sec", x)
             },
+            LogValue::Timestamp(x) => {
+                x.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+            },
+            LogValue::TopK(x) => {
+                let items: Vec<String> = x.iter()
+                    .map(|(v, c)| format!("{}({})", v, c))
+                    .collect();
+                items.join(" ")
+            },
+            LogValue::Histogram(x) => {
+                let items: Vec<String> = x.iter()
+                    .map(|b| format!("{}:{}", b.label(), b.count))
+                    .collect();
+                items.join(" ")
+            },
             LogValue::None => {
                 String::from("-")
             }
         };
         s
     }
+
+    /// Order of types when values of different types are compared.
+    fn type_rank(&self) -> u8 {
+        match self {
+            LogValue::String(_) => 0,
+            LogValue::Integer(_) | LogValue::Float(_) | LogValue::Second(_) => 1,
+            LogValue::Timestamp(_) => 2,
+            LogValue::TopK(_) => 3,
+            LogValue::Histogram(_) => 4,
+            LogValue::None => 5,
+        }
+    }
+
+    /// Numeric value of integer, float and second.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            LogValue::Integer(x) => Some(*x as f64),
+            LogValue::Float(x) => Some(*x),
+            LogValue::Second(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    /// Convert into json value for machine-readable outputs.
+    /// Seconds are converted into numbers.
+    pub fn to_json(&self) -> Value {
+        match self {
+            LogValue::String(x) => json!(x),
+            LogValue::Integer(x) => json!(x),
+            LogValue::Float(x) => json!(x),
+            LogValue::Second(x) => json!(x),
+            LogValue::Timestamp(_) => json!(self.as_string()),
+            LogValue::TopK(x) => {
+                let items: Vec<Value> = x.iter()
+                    .map(|(v, c)| json!({ "value": v, "count": c }))
+                    .collect();
+                Value::Array(items)
+            },
+            LogValue::Histogram(x) => {
+                let items: Vec<Value> = x.iter()
+                    .map(|b| json!({ "lower": b.lower, "upper": b.upper, "count": b.count }))
+                    .collect();
+                Value::Array(items)
+            },
+            LogValue::None => Value::Null,
+        }
+    }
 }
 
 fn get_value(v :&Value, accessor: &[String], pos: usize) -> Option<String>{
     if accessor.len() == pos {
-        return v.as_str().map(String::from);
+        return match v {
+            Value::String(s) => Some(s.clone()),
+            Value::Number(n) => Some(n.to_string()),
+            Value::Bool(b) => Some(b.to_string()),
+            _ => None,
+        };
     }
     let key = &accessor[pos];
     let nxt = &v[key];
     get_value(nxt, accessor, pos+1)
 }
 
-fn parse_value(typ: &LogValueType, s :&str) -> LogValue {
+pub fn parse_value(typ: &LogValueType, s :&str) -> LogValue {
     match typ {
         LogValueType::String => {
             LogValue::String(s.to_string())
@@ -240,10 +368,25 @@ fn parse_value(typ: &LogValueType, s :&str) -> LogValue {
                 LogValue::None
             }
         },
+        LogValueType::Timestamp => {
+            parse_timestamp(s).map_or(LogValue::None, LogValue::Timestamp)
+        },
         _ => { LogValue::None }
     }
 }
 
+/// Parse RFC3339 string or unix time in seconds.
+fn parse_timestamp(s: &str) -> Option<DateTime<FixedOffset>> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Some(t);
+    }
+    let num = s.parse::<f64>().ok().filter(|n| n.is_finite())?;
+    let secs = num.floor();
+    let nanos = ((num - secs) * 1e9).round() as u32;
+    let t = Utc.timestamp_opt(secs as i64, nanos).single()?;
+    Some(t.with_timezone(&FixedOffset::east_opt(0)?))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -287,5 +430,48 @@ mod tests {
         }
         let v = parse_value(&LogValueType::Second, "123.4h");
         assert!(matches!(v, LogValue::None));
+
+        // Check timestamp case.
+        let v = parse_value(&LogValueType::Timestamp, "2022-06-05T12:34:56.5+09:00");
+        if let LogValue::Timestamp(t) = v {
+            assert_eq!(t.timestamp_millis(), 1654400096500);
+        } else {
+            unreachable!();
+        }
+        let v = parse_value(&LogValueType::Timestamp, "1654400096.5");
+        if let LogValue::Timestamp(t) = v {
+            assert_eq!(t.timestamp_millis(), 1654400096500);
+        } else {
+            unreachable!();
+        }
+        let v = parse_value(&LogValueType::Timestamp, "yesterday");
+        assert!(matches!(v, LogValue::None));
+        for s in ["NaN", "inf", "-infinity", "1e300"] {
+            let v = parse_value(&LogValueType::Timestamp, s);
+            assert!(matches!(v, LogValue::None), "{}", s);
+        }
+    }
+
+    #[test]
+    fn check_eq_and_cmp() {
+        assert_eq!(LogValue::None, LogValue::None);
+        assert_eq!(LogValue::Integer(1), LogValue::Float(1.));
+        assert_eq!(LogValue::Integer(1).cmp(&LogValue::Float(1.)), Ordering::Equal);
+        assert_ne!(LogValue::Second(1.), LogValue::Second(2.));
+        assert_ne!(LogValue::Second(2.), LogValue::Second(1.));
+        assert_ne!(LogValue::Float(0.), LogValue::None);
+        assert!(LogValue::Float(1e9) < LogValue::None);
+        assert_ne!(LogValue::String(String::from("1")), LogValue::Integer(1));
+    }
+
+    #[test]
+    fn check_get_value() {
+        let v: Value = serde_json::from_str(r#"{"a": {"b": "x", "c": 200, "d": true}}"#).unwrap();
+        let path = |s: &str| -> Vec<String> { s.split('.').map(String::from).collect() };
+        assert_eq!(get_value(&v, &path("a.b"), 0), Some(String::from("x")));
+        assert_eq!(get_value(&v, &path("a.c"), 0), Some(String::from("200")));
+        assert_eq!(get_value(&v, &path("a.d"), 0), Some(String::from("true")));
+        assert_eq!(get_value(&v, &path("a.e"), 0), None);
+        assert_eq!(get_value(&v, &path("a"), 0), None);
     }
 }
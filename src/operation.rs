@@ -1,16 +1,113 @@
 
 
-use crate::log_record::{ LogValue };
+use crate::expression::{ Expr, Predicate };
+use crate::log_record::{ Accessor, Bucket, LogRecord, LogValue };
+use crate::sketch::{ HyperLogLog, QuantileSketch, SpaceSaving };
+
+use std::any::{ Any };
+use std::cmp::{ Ordering };
+use std::collections::{ BTreeMap, HashSet };
 
 #[derive(Clone)]
 pub enum OpType {
     Count,
+    Sum,
     Average,
+    Min,
+    Max,
+    /// Percentile with a rank between 0 and 100.
+    /// `exact` keeps every value instead of using a sketch.
+    Percentile { rank: f64, exact: bool },
+    /// Number of distinct values.
+    /// Estimated by HyperLogLog with `precision` unless `exact` is set.
+    CountDistinct { exact: bool, precision: u8 },
+    Variance,
+    StdDev,
+    /// Coefficient of variation. (standard deviation / mean)
+    Cv,
+    /// The `k` most frequent values.
+    TopK { k: usize },
+    /// Value of the first record, ordered by input position or by the `time` property.
+    First { time: Option<Accessor> },
+    /// Value of the last record, ordered by input position or by the `time` property.
+    Last { time: Option<Accessor> },
+    /// Earliest timestamp.
+    FirstSeen,
+    /// Latest timestamp.
+    LastSeen,
+    /// Count of records matching the condition.
+    CountIf { condition: Predicate },
+    /// Average of records matching the condition.
+    AverageIf { condition: Predicate },
+    /// Share of records matching the condition.
+    Ratio { condition: Predicate },
+    /// Counts of values per bucket. Buckets are split at `bounds`,
+    /// or at powers of two when `bounds` is not given.
+    Histogram { bounds: Option<Vec<f64>> },
+    /// Apdex score with the target threshold.
+    Apdex { threshold: f64 },
+    /// Share of records meeting the objective.
+    Slo { objective: Objective },
+    /// Remaining error budget of the objective for `target` (0 < target < 1).
+    ErrorBudget { objective: Objective, target: f64 },
+    /// Expression over other fields of the row, computed after aggregation.
+    Expression { expr: Expr },
+}
+
+impl OpType {
+    /// Whether the operation scales its result by weights of records.
+    pub fn supports_weight(&self) -> bool {
+        matches!(self, OpType::Count | OpType::Sum | OpType::Average
+            | OpType::CountIf { .. } | OpType::AverageIf { .. } | OpType::Ratio { .. })
+    }
+}
+
+/// Service level objective. A record is good when it matches `condition`
+/// and its value is not greater than `threshold`.
+#[derive(Clone)]
+pub struct Objective {
+    pub condition: Option<Predicate>,
+    pub threshold: Option<f64>,
+}
+
+impl Objective {
+    fn is_good(&self, v: &LogValue, record: &LogRecord) -> bool {
+        if let Some(threshold) = self.threshold {
+            match v.as_f64() {
+                Some(x) if x <= threshold => {},
+                _ => { return false; }
+            }
+        }
+        match &self.condition {
+            Some(c) => c.eval(&|path| record.resolve(path)),
+            None => true
+        }
+    }
 }
 
 pub trait Operation {
     fn update(&mut self,  v :&LogValue); 
     fn value(&self) -> LogValue;
+
+    /// Update with the whole record, for operations which refer to other properties.
+    fn update_with_record(&mut self, v: &LogValue, _record: &LogRecord) {
+        self.update(v);
+    }
+
+    /// Update with the weight of the record.
+    /// Only called for operations whose `OpType::supports_weight` is true.
+    ///
+    /// A record counts as `weight` records, and `update` counts as a weight
+    /// of 1, so counts of weighted operations are sums of weights.
+    fn update_weighted(&mut self, v: &LogValue, _weight: f64, record: &LogRecord) {
+        self.update_with_record(v, record);
+    }
+
+    /// Merge the state of another operation built from the same `OpType`,
+    /// as if this operation had also seen the records of the other.
+    fn merge(&mut self, other: &dyn Operation);
+
+    fn as_any(&self) -> &dyn Any;
 }
 
 
@@ -19,72 +116,1029 @@ pub fn build_operation(op_type: &OpType) -> Box<dyn Operation> {
         OpType::Count => {
             Box::new(OpCount::new())
         },
+        OpType::Sum => {
+            Box::new(OpSum::new())
+        },
         OpType::Average => {
             Box::new(OpAverage::new())
-        } 
+        },
+        OpType::Min => {
+            Box::new(OpExtreme::new(false))
+        },
+        OpType::Max => {
+            Box::new(OpExtreme::new(true))
+        },
+        OpType::Percentile { rank, exact } => {
+            Box::new(OpPercentile::new(*rank, *exact))
+        },
+        OpType::CountDistinct { exact, precision } => {
+            Box::new(OpCountDistinct::new(*exact, *precision))
+        },
+        OpType::Variance | OpType::StdDev | OpType::Cv => {
+            Box::new(OpDeviation::new(op_type.clone()))
+        },
+        OpType::TopK { k } => {
+            Box::new(OpTopK::new(*k))
+        },
+        OpType::First { time } => {
+            Box::new(OpFirstLast::new(false, time.clone()))
+        },
+        OpType::Last { time } => {
+            Box::new(OpFirstLast::new(true, time.clone()))
+        },
+        OpType::FirstSeen => {
+            Box::new(OpSeen::new(false))
+        },
+        OpType::LastSeen => {
+            Box::new(OpSeen::new(true))
+        },
+        OpType::CountIf { condition } => {
+            Box::new(OpCondition::new(condition.clone(), Box::new(OpCount::new())))
+        },
+        OpType::AverageIf { condition } => {
+            Box::new(OpCondition::new(condition.clone(), Box::new(OpAverage::new())))
+        },
+        OpType::Ratio { condition } => {
+            Box::new(OpRatio::new(condition.clone()))
+        },
+        OpType::Histogram { bounds } => {
+            Box::new(OpHistogram::new(bounds.clone()))
+        },
+        OpType::Apdex { threshold } => {
+            Box::new(OpApdex::new(*threshold))
+        },
+        OpType::Slo { objective } => {
+            Box::new(OpSlo::new(objective.clone(), None))
+        },
+        OpType::ErrorBudget { objective, target } => {
+            Box::new(OpSlo::new(objective.clone(), Some(*target)))
+        },
+        // Computed by the row from other fields, so nothing is aggregated.
+        OpType::Expression { .. } => {
+            Box::new(OpNone)
+        }
+    }
+}
+
+/// Operation which ignores values.
+pub struct OpNone;
+
+impl Operation for OpNone {
+    fn update(&mut self, _v: &LogValue) {}
+
+    fn value(&self) -> LogValue {
+        LogValue::None
+    }
+
+    fn merge(&mut self, _other: &dyn Operation) {}
+
+    fn as_any(&self) -> &dyn Any {
+        self
     }
 }
 
 #[derive(Clone)]
 pub struct OpCount {
-    count: u32
+    count: f64
 }
 
 impl Operation for OpCount {
     fn update(&mut self, v :&LogValue) {
         match v {
             LogValue::None => {},
-            _ => { self.count += 1 }
+            _ => { self.count += 1. }
+        };
+    }
+
+    fn update_weighted(&mut self, v: &LogValue, weight: f64, _record: &LogRecord) {
+        match v {
+            LogValue::None => {},
+            _ => { self.count += weight }
         };
     }
 
     fn value(&self) -> LogValue{
-        LogValue::Integer(self.count)
+        count_value(self.count)
+    }
+
+    fn merge(&mut self, other: &dyn Operation) {
+        if let Some(o) = other.as_any().downcast_ref::<Self>() {
+            self.count += o.count;
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
     }
 }
 
 impl OpCount {
     pub fn new() -> Self {
-        Self { count: 0 }
+        Self { count: 0. }
     }
 }
 
-pub struct OpAverage {
+pub struct OpSum {
     sum: f64,
     count: u32,
+    // Whether all inputs are integers, to keep integer on output.
+    integer: bool,
+    unit: Unit,
+}
+
+impl Operation for OpSum {
+    fn update(&mut self, v: &LogValue) {
+        self.add(v, 1.);
+    }
+
+    fn update_weighted(&mut self, v: &LogValue, weight: f64, _record: &LogRecord) {
+        self.add(v, weight);
+    }
+
+    fn value(&self) -> LogValue {
+        if self.count == 0 {
+            return LogValue::None;
+        }
+        if self.integer {
+            count_value(self.sum)
+        } else {
+            self.unit.value(self.sum)
+        }
+    }
+
+    fn merge(&mut self, other: &dyn Operation) {
+        if let Some(o) = other.as_any().downcast_ref::<Self>() {
+            self.sum += o.sum;
+            self.count += o.count;
+            self.integer &= o.integer;
+            self.unit.merge(o.unit);
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl OpSum {
+    pub fn new() -> Self {
+        Self { sum: 0., count: 0, integer: true, unit: Unit::default() }
+    }
+
+    fn add(&mut self, v: &LogValue, weight: f64) {
+        let x = match self.unit.number(v) {
+            Some(x) => x,
+            None => { return; }
+        };
+        self.sum += x * weight;
+        self.integer &= matches!(v, LogValue::Integer(_));
+        self.count += 1;
+    }
+}
+
+pub struct OpAverage {
+    sum: f64,
+    count: f64,
 }
 
 impl Operation for OpAverage {
     fn update(&mut self, v: &LogValue) {
+        self.add(v, 1.);
+    }
+
+    fn update_weighted(&mut self, v: &LogValue, weight: f64, _record: &LogRecord) {
+        self.add(v, weight);
+    }
+
+    fn value(&self) -> LogValue{
+        if self.count == 0. {
+           return LogValue::None; 
+        }
+        LogValue::Float(self.sum/self.count)
+    }
+
+    fn merge(&mut self, other: &dyn Operation) {
+        if let Some(o) = other.as_any().downcast_ref::<Self>() {
+            self.sum += o.sum;
+            self.count += o.count;
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl OpAverage {
+    pub fn new() -> Self {
+        Self { sum: 0., count: 0. }
+    }
+
+    fn add(&mut self, v: &LogValue, weight: f64) {
         match v {
             LogValue::Integer(x) => {
-                self.sum += *x as f64;
-                self.count += 1;
+                self.sum += *x as f64 * weight;
+                self.count += weight;
             },
             LogValue::Float(x) => {
-                self.sum += *x;
-                self.count += 1;
+                self.sum += *x * weight;
+                self.count += weight;
             },
             LogValue::Second(x) => {
-                self.sum += *x;
-                self.count += 1;
+                self.sum += *x * weight;
+                self.count += weight;
             }
             _ => { 
             }
         };
     }
+}
 
-    fn value(&self) -> LogValue{
-        if self.count == 0 {
-           return LogValue::None; 
+/// Minimum or maximum of numbers, keeping the unit of the value.
+pub struct OpExtreme {
+    max: bool,
+    current: Option<(f64, LogValue)>,
+}
+
+impl Operation for OpExtreme {
+    fn update(&mut self, v: &LogValue) {
+        let x = match v.as_f64() {
+            Some(x) if !x.is_nan() => x,
+            _ => { return; }
+        };
+        let replace = match &self.current {
+            Some((c, _)) if self.max => x > *c,
+            Some((c, _)) => x < *c,
+            None => true
+        };
+        if replace {
+            self.current = Some((x, v.clone()));
+        }
+    }
+
+    fn value(&self) -> LogValue {
+        match &self.current {
+            Some((_, v)) => v.clone(),
+            None => LogValue::None,
         }
-        LogValue::Float(self.sum/(self.count as f64))
+    }
+
+    fn merge(&mut self, other: &dyn Operation) {
+        if let Some(o) = other.as_any().downcast_ref::<Self>() {
+            if let Some((_, v)) = &o.current {
+                self.update(v);
+            }
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
     }
 }
 
-impl OpAverage {
+impl OpExtreme {
+    pub fn new(max: bool) -> Self {
+        Self { max, current: None }
+    }
+}
+
+/// Integer if the count is a whole number which fits, otherwise float.
+fn count_value(count: f64) -> LogValue {
+    if count.fract() == 0. && count >= 0. && count <= u32::MAX as f64 {
+        LogValue::Integer(count as u32)
+    } else {
+        LogValue::Float(count)
+    }
+}
+
+/// Unit of the numeric inputs of an operation.
+///
+/// Seconds are kept as seconds on outputs in the same dimension as the
+/// inputs, such as sums, percentiles and standard deviations. Outputs of
+/// another dimension, such as variances or ratios, are always floats.
+#[derive(Clone, Copy, Default)]
+struct Unit {
+    second: bool,
+}
+
+impl Unit {
+    /// Numeric value of the input, or `None` if it is not a number.
+    fn number(&mut self, v: &LogValue) -> Option<f64> {
+        match v {
+            LogValue::Integer(x) => Some(*x as f64),
+            LogValue::Float(x) => Some(*x),
+            LogValue::Second(x) => {
+                self.second = true;
+                Some(*x)
+            },
+            _ => None,
+        }
+    }
+
+    fn merge(&mut self, other: Unit) {
+        self.second |= other.second;
+    }
+
+    /// Output in the unit of the inputs.
+    fn value(&self, x: f64) -> LogValue {
+        if self.second { LogValue::Second(x) } else { LogValue::Float(x) }
+    }
+}
+
+enum PercentileStore {
+    Exact(Vec<f64>),
+    Sketch(QuantileSketch),
+}
+
+pub struct OpPercentile {
+    rank: f64,
+    store: PercentileStore,
+    unit: Unit,
+}
+
+impl Operation for OpPercentile {
+    fn update(&mut self, v: &LogValue) {
+        let x = match self.unit.number(v) {
+            Some(x) => x,
+            None => { return; }
+        };
+        match &mut self.store {
+            PercentileStore::Exact(values) => values.push(x),
+            PercentileStore::Sketch(sketch) => sketch.add(x),
+        }
+    }
+
+    fn value(&self) -> LogValue {
+        let q = self.rank / 100.;
+        let result = match &self.store {
+            PercentileStore::Exact(values) => exact_quantile(values, q),
+            PercentileStore::Sketch(sketch) => sketch.quantile(q),
+        };
+        result.map_or(LogValue::None, |x| self.unit.value(x))
+    }
+
+    fn merge(&mut self, other: &dyn Operation) {
+        if let Some(o) = other.as_any().downcast_ref::<Self>() {
+            match (&mut self.store, &o.store) {
+                (PercentileStore::Exact(values), PercentileStore::Exact(x)) => values.extend(x),
+                (PercentileStore::Sketch(sketch), PercentileStore::Sketch(x)) => sketch.merge(x),
+                _ => {}
+            }
+            self.unit.merge(o.unit);
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl OpPercentile {
+    pub fn new(rank: f64, exact: bool) -> Self {
+        let store = if exact {
+            PercentileStore::Exact(Vec::new())
+        } else {
+            PercentileStore::Sketch(QuantileSketch::default())
+        };
+        Self { rank, store, unit: Unit::default() }
+    }
+}
+
+/// Quantile with linear interpolation between the closest ranks.
+fn exact_quantile(values: &[f64], q: f64) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let pos = q * (sorted.len() - 1) as f64;
+    let lower = pos.floor() as usize;
+    let upper = pos.ceil() as usize;
+    let frac = pos - lower as f64;
+    Some(sorted[lower] + (sorted[upper] - sorted[lower]) * frac)
+}
+
+enum DistinctStore {
+    Exact(HashSet<String>),
+    Approximate(HyperLogLog),
+}
+
+pub struct OpCountDistinct {
+    store: DistinctStore,
+}
+
+impl Operation for OpCountDistinct {
+    fn update(&mut self, v: &LogValue) {
+        if let LogValue::None = v {
+            return;
+        }
+        let s = v.as_string();
+        match &mut self.store {
+            DistinctStore::Exact(values) => {
+                values.insert(s);
+            },
+            DistinctStore::Approximate(hll) => hll.add(&s),
+        }
+    }
+
+    fn value(&self) -> LogValue {
+        match &self.store {
+            DistinctStore::Exact(values) => LogValue::Integer(values.len() as u32),
+            DistinctStore::Approximate(hll) => LogValue::Integer(hll.estimate().round() as u32),
+        }
+    }
+
+    fn merge(&mut self, other: &dyn Operation) {
+        if let Some(o) = other.as_any().downcast_ref::<Self>() {
+            match (&mut self.store, &o.store) {
+                (DistinctStore::Exact(values), DistinctStore::Exact(x)) => values.extend(x.iter().cloned()),
+                (DistinctStore::Approximate(hll), DistinctStore::Approximate(x)) => hll.merge(x),
+                _ => {}
+            }
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl OpCountDistinct {
+    pub fn new(exact: bool, precision: u8) -> Self {
+        let store = if exact {
+            DistinctStore::Exact(HashSet::new())
+        } else {
+            DistinctStore::Approximate(HyperLogLog::new(precision))
+        };
+        Self { store }
+    }
+}
+
+/// Running mean and variance computed by Welford's online algorithm.
+#[derive(Clone, Default)]
+pub struct Welford {
+    count: u32,
+    mean: f64,
+    m2: f64,
+}
+
+impl Welford {
     pub fn new() -> Self {
-        Self { sum: 0., count: 0 }
+        Self::default()
+    }
+
+    pub fn add(&mut self, x: f64) {
+        self.count += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(self.mean)
+    }
+
+    /// Combine statistics of another set of values by Chan's parallel algorithm.
+    pub fn merge(&mut self, other: &Welford) {
+        if other.count == 0 {
+            return;
+        }
+        let count = self.count + other.count;
+        let delta = other.mean - self.mean;
+        self.m2 += other.m2 + delta * delta * self.count as f64 * other.count as f64 / count as f64;
+        self.mean += delta * other.count as f64 / count as f64;
+        self.count = count;
+    }
+
+    /// Unbiased sample variance.
+    pub fn variance(&self) -> Option<f64> {
+        if self.count < 2 {
+            return None;
+        }
+        Some(self.m2 / (self.count - 1) as f64)
+    }
+}
+
+/// Variance, standard deviation and coefficient of variation.
+pub struct OpDeviation {
+    op_type: OpType,
+    stats: Welford,
+    unit: Unit,
+}
+
+impl Operation for OpDeviation {
+    fn update(&mut self, v: &LogValue) {
+        if let Some(x) = self.unit.number(v) {
+            self.stats.add(x);
+        }
+    }
+
+    fn value(&self) -> LogValue {
+        let variance = match self.stats.variance() {
+            Some(x) => x,
+            None => { return LogValue::None; }
+        };
+        match self.op_type {
+            OpType::Variance => LogValue::Float(variance),
+            OpType::StdDev => self.unit.value(variance.sqrt()),
+            _ => {
+                match self.stats.mean() {
+                    Some(mean) if mean != 0. => LogValue::Float(variance.sqrt() / mean.abs()),
+                    _ => LogValue::None,
+                }
+            }
+        }
+    }
+
+    fn merge(&mut self, other: &dyn Operation) {
+        if let Some(o) = other.as_any().downcast_ref::<Self>() {
+            self.stats.merge(&o.stats);
+            self.unit.merge(o.unit);
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl OpDeviation {
+    pub fn new(op_type: OpType) -> Self {
+        Self { op_type, stats: Welford::new(), unit: Unit::default() }
+    }
+}
+
+/// Number of counters kept by `OpTopK` per reported value.
+const TOP_K_CAPACITY_FACTOR: usize = 10;
+/// Maximum `k` of `top_k`, to bound the number of counters per row.
+pub const MAX_TOP_K: usize = 10_000;
+
+pub struct OpTopK {
+    k: usize,
+    counter: SpaceSaving,
+}
+
+impl Operation for OpTopK {
+    fn update(&mut self, v: &LogValue) {
+        if let LogValue::None = v {
+            return;
+        }
+        self.counter.add(&v.as_string());
+    }
+
+    fn value(&self) -> LogValue {
+        let top = self.counter.top(self.k);
+        if top.is_empty() {
+            return LogValue::None;
+        }
+        LogValue::TopK(top)
+    }
+
+    fn merge(&mut self, other: &dyn Operation) {
+        if let Some(o) = other.as_any().downcast_ref::<Self>() {
+            self.counter.merge(&o.counter);
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl OpTopK {
+    pub fn new(k: usize) -> Self {
+        let capacity = k.saturating_mul(TOP_K_CAPACITY_FACTOR);
+        Self { k, counter: SpaceSaving::new(capacity) }
+    }
+}
+
+pub struct OpFirstLast {
+    last: bool,
+    time: Option<Accessor>,
+    // (time, position, value) of the selected record.
+    current: Option<(LogValue, u64, LogValue)>,
+    // Fallback position when updated without records.
+    seen: u64,
+}
+
+impl Operation for OpFirstLast {
+    fn update(&mut self, v: &LogValue) {
+        let position = self.seen;
+        self.seen += 1;
+        self.select(LogValue::None, position, v);
+    }
+
+    fn update_with_record(&mut self, v: &LogValue, record: &LogRecord) {
+        let time = match &self.time {
+            Some(accessor) => {
+                let t = record.lookup(accessor);
+                // Records without valid time can not be ordered.
+                if let LogValue::None = t {
+                    return;
+                }
+                t
+            },
+            None => LogValue::None
+        };
+        self.select(time, record.position, v);
+    }
+
+    fn value(&self) -> LogValue {
+        match &self.current {
+            Some((_, _, v)) => v.clone(),
+            None => LogValue::None,
+        }
+    }
+
+    fn merge(&mut self, other: &dyn Operation) {
+        if let Some(o) = other.as_any().downcast_ref::<Self>() {
+            if let Some((t, p, v)) = &o.current {
+                self.select(t.clone(), *p, v);
+            }
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl OpFirstLast {
+    pub fn new(last: bool, time: Option<Accessor>) -> Self {
+        Self { last, time, current: None, seen: 0 }
+    }
+
+    fn select(&mut self, time: LogValue, position: u64, v: &LogValue) {
+        if let LogValue::None = v {
+            return;
+        }
+        let replace = match &self.current {
+            Some((t, p, _)) => {
+                let ord = time.cmp(t).then(position.cmp(p));
+                if self.last { ord == Ordering::Greater } else { ord == Ordering::Less }
+            },
+            None => true
+        };
+        if replace {
+            self.current = Some((time, position, v.clone()));
+        }
+    }
+}
+
+/// Earliest or latest timestamp.
+pub struct OpSeen {
+    latest: bool,
+    current: Option<LogValue>,
+}
+
+impl Operation for OpSeen {
+    fn update(&mut self, v: &LogValue) {
+        if !matches!(v, LogValue::Timestamp(_)) {
+            return;
+        }
+        let replace = match &self.current {
+            Some(c) if self.latest => v > c,
+            Some(c) => v < c,
+            None => true
+        };
+        if replace {
+            self.current = Some(v.clone());
+        }
+    }
+
+    fn value(&self) -> LogValue {
+        self.current.clone().unwrap_or(LogValue::None)
+    }
+
+    fn merge(&mut self, other: &dyn Operation) {
+        if let Some(o) = other.as_any().downcast_ref::<Self>() {
+            if let Some(v) = &o.current {
+                self.update(v);
+            }
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl OpSeen {
+    pub fn new(latest: bool) -> Self {
+        Self { latest, current: None }
+    }
+}
+
+/// Applies the inner operation only to records matching the condition.
+pub struct OpCondition {
+    condition: Predicate,
+    inner: Box<dyn Operation>,
+}
+
+impl Operation for OpCondition {
+    // Without the record the condition can not be evaluated, so the value is ignored.
+    fn update(&mut self, _v: &LogValue) {}
+
+    fn update_with_record(&mut self, v: &LogValue, record: &LogRecord) {
+        if self.condition.eval(&|path| record.resolve(path)) {
+            self.inner.update_with_record(v, record);
+        }
+    }
+
+    fn update_weighted(&mut self, v: &LogValue, weight: f64, record: &LogRecord) {
+        if self.condition.eval(&|path| record.resolve(path)) {
+            self.inner.update_weighted(v, weight, record);
+        }
+    }
+
+    fn value(&self) -> LogValue {
+        self.inner.value()
+    }
+
+    fn merge(&mut self, other: &dyn Operation) {
+        if let Some(o) = other.as_any().downcast_ref::<Self>() {
+            self.inner.merge(o.inner.as_ref());
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl OpCondition {
+    pub fn new(condition: Predicate, inner: Box<dyn Operation>) -> Self {
+        Self { condition, inner }
+    }
+}
+
+/// Share of valid values whose record matches the condition.
+pub struct OpRatio {
+    condition: Predicate,
+    matched: f64,
+    total: f64,
+}
+
+impl Operation for OpRatio {
+    fn update(&mut self, v: &LogValue) {
+        if let LogValue::None = v {
+            return;
+        }
+        self.total += 1.;
+    }
+
+    fn update_with_record(&mut self, v: &LogValue, record: &LogRecord) {
+        self.update_weighted(v, 1., record);
+    }
+
+    fn update_weighted(&mut self, v: &LogValue, weight: f64, record: &LogRecord) {
+        if let LogValue::None = v {
+            return;
+        }
+        self.total += weight;
+        if self.condition.eval(&|path| record.resolve(path)) {
+            self.matched += weight;
+        }
+    }
+
+    fn value(&self) -> LogValue {
+        if self.total == 0. {
+            return LogValue::None;
+        }
+        LogValue::Float(self.matched / self.total)
+    }
+
+    fn merge(&mut self, other: &dyn Operation) {
+        if let Some(o) = other.as_any().downcast_ref::<Self>() {
+            self.matched += o.matched;
+            self.total += o.total;
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl OpRatio {
+    pub fn new(condition: Predicate) -> Self {
+        Self { condition, matched: 0., total: 0. }
+    }
+}
+
+enum HistogramStore {
+    /// Counts for each interval split at the bounds. Its length is `bounds.len() + 1`.
+    Fixed { bounds: Vec<f64>, counts: Vec<u64> },
+    /// Counts of `[2^i, 2^(i+1))` keyed by `i`, and counts of non-positive values.
+    Log { counts: BTreeMap<i32, u64>, non_positive: u64 },
+}
+
+pub struct OpHistogram {
+    store: HistogramStore,
+}
+
+impl Operation for OpHistogram {
+    fn update(&mut self, v: &LogValue) {
+        let x = match v {
+            LogValue::Integer(x) => *x as f64,
+            LogValue::Float(x) => *x,
+            LogValue::Second(x) => *x,
+            _ => { return; }
+        };
+        // Infinity has no log-scale bucket, and would expand buckets without end.
+        if !x.is_finite() {
+            return;
+        }
+        match &mut self.store {
+            HistogramStore::Fixed { bounds, counts } => {
+                let i = bounds.partition_point(|b| *b <= x);
+                counts[i] += 1;
+            },
+            HistogramStore::Log { counts, non_positive } => {
+                if x > 0. {
+                    *counts.entry(x.log2().floor() as i32).or_insert(0) += 1;
+                } else {
+                    *non_positive += 1;
+                }
+            }
+        }
+    }
+
+    fn value(&self) -> LogValue {
+        let mut buckets: Vec<Bucket> = vec![];
+        match &self.store {
+            HistogramStore::Fixed { bounds, counts } => {
+                if counts.iter().all(|c| *c == 0) {
+                    return LogValue::None;
+                }
+                for (i, count) in counts.iter().enumerate() {
+                    let lower = if i == 0 { None } else { Some(bounds[i - 1]) };
+                    let upper = bounds.get(i).copied();
+                    buckets.push(Bucket { lower, upper, count: *count });
+                }
+            },
+            HistogramStore::Log { counts, non_positive } => {
+                let (min, max) = match (counts.keys().next(), counts.keys().next_back()) {
+                    (Some(min), Some(max)) => (*min, *max),
+                    _ if *non_positive > 0 => {
+                        return LogValue::Histogram(vec![
+                            Bucket { lower: None, upper: None, count: *non_positive }]);
+                    },
+                    _ => { return LogValue::None; }
+                };
+                if *non_positive > 0 {
+                    buckets.push(Bucket { lower: None, upper: Some(2f64.powi(min)), count: *non_positive });
+                }
+                // Include empty buckets in between to keep the shape of distribution.
+                for i in min..=max {
+                    let count = counts.get(&i).copied().unwrap_or(0);
+                    buckets.push(Bucket { lower: Some(2f64.powi(i)), upper: Some(2f64.powi(i + 1)), count });
+                }
+            }
+        }
+        LogValue::Histogram(buckets)
+    }
+
+    fn merge(&mut self, other: &dyn Operation) {
+        if let Some(o) = other.as_any().downcast_ref::<Self>() {
+            match (&mut self.store, &o.store) {
+                (HistogramStore::Fixed { counts, .. }, HistogramStore::Fixed { counts: x, .. }) => {
+                    for (c, x) in counts.iter_mut().zip(x.iter()) {
+                        *c += x;
+                    }
+                },
+                (HistogramStore::Log { counts, non_positive }, HistogramStore::Log { counts: x, non_positive: y }) => {
+                    for (i, c) in x.iter() {
+                        *counts.entry(*i).or_insert(0) += c;
+                    }
+                    *non_positive += y;
+                },
+                _ => {}
+            }
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl OpHistogram {
+    /// `bounds` must be sorted in ascending order.
+    pub fn new(bounds: Option<Vec<f64>>) -> Self {
+        let store = match bounds {
+            Some(bounds) => {
+                let counts = vec![0; bounds.len() + 1];
+                HistogramStore::Fixed { bounds, counts }
+            },
+            None => HistogramStore::Log { counts: BTreeMap::new(), non_positive: 0 }
+        };
+        Self { store }
+    }
+}
+
+/// Apdex = (satisfied + tolerating / 2) / total.
+/// Values up to the threshold are satisfied and up to 4 times of it are tolerating.
+pub struct OpApdex {
+    threshold: f64,
+    satisfied: u32,
+    tolerating: u32,
+    total: u32,
+}
+
+impl Operation for OpApdex {
+    fn update(&mut self, v: &LogValue) {
+        let x = match v.as_f64() {
+            Some(x) => x,
+            None => { return; }
+        };
+        self.total += 1;
+        if x <= self.threshold {
+            self.satisfied += 1;
+        } else if x <= self.threshold * 4. {
+            self.tolerating += 1;
+        }
+    }
+
+    fn value(&self) -> LogValue {
+        if self.total == 0 {
+            return LogValue::None;
+        }
+        let score = (self.satisfied as f64 + self.tolerating as f64 / 2.) / self.total as f64;
+        LogValue::Float(score)
+    }
+
+    fn merge(&mut self, other: &dyn Operation) {
+        if let Some(o) = other.as_any().downcast_ref::<Self>() {
+            self.satisfied += o.satisfied;
+            self.tolerating += o.tolerating;
+            self.total += o.total;
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl OpApdex {
+    pub fn new(threshold: f64) -> Self {
+        Self { threshold, satisfied: 0, tolerating: 0, total: 0 }
+    }
+}
+
+/// Compliance of the objective, or remaining error budget when `target` is given.
+pub struct OpSlo {
+    objective: Objective,
+    target: Option<f64>,
+    good: u32,
+    total: u32,
+}
+
+impl Operation for OpSlo {
+    // Without the record the objective can not be evaluated, so the value is ignored.
+    fn update(&mut self, _v: &LogValue) {}
+
+    fn update_with_record(&mut self, v: &LogValue, record: &LogRecord) {
+        if let LogValue::None = v {
+            return;
+        }
+        self.total += 1;
+        if self.objective.is_good(v, record) {
+            self.good += 1;
+        }
+    }
+
+    fn value(&self) -> LogValue {
+        if self.total == 0 {
+            return LogValue::None;
+        }
+        let compliance = self.good as f64 / self.total as f64;
+        match self.target {
+            // Share of allowed bad records which is not consumed yet. Negative when exhausted.
+            Some(target) => LogValue::Float(1. - (1. - compliance) / (1. - target)),
+            None => LogValue::Float(compliance),
+        }
+    }
+
+    fn merge(&mut self, other: &dyn Operation) {
+        if let Some(o) = other.as_any().downcast_ref::<Self>() {
+            self.good += o.good;
+            self.total += o.total;
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl OpSlo {
+    pub fn new(objective: Objective, target: Option<f64>) -> Self {
+        Self { objective, target, good: 0, total: 0 }
     }
 }
 
@@ -92,6 +1146,8 @@ impl OpAverage {
 mod tests {
 
     use super::*;
+    use crate::log_record::{ LogValueType };
+    use chrono::{ DateTime };
     
     #[test]
     fn check_op_average() {
@@ -106,6 +1162,303 @@ mod tests {
         }
     }
 
+    #[test]
+    fn check_op_percentile() {
+        let mut op = OpPercentile::new(90., true);
+        assert!(matches!(op.value(), LogValue::None));
+        for n in 1..12 {
+            op.update(&LogValue::Integer(n));
+        }
+        if let LogValue::Float(x) = op.value() {
+            assert_eq!(x, 10.);
+        } else {
+            unreachable!();
+        }
+
+        let mut op = OpPercentile::new(50., false);
+        for n in 1..102 {
+            op.update(&LogValue::Second(n as f64));
+        }
+        if let LogValue::Second(x) = op.value() {
+            assert!((x - 51.).abs() <= 51. * 0.01);
+        } else {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn check_op_count_distinct() {
+        for exact in [true, false] {
+            let mut op = OpCountDistinct::new(exact, 12);
+            for n in 0..100 {
+                op.update(&LogValue::String(format!("user{}", n % 7)));
+            }
+            op.update(&LogValue::None);
+            if let LogValue::Integer(x) = op.value() {
+                assert_eq!(x, 7);
+            } else {
+                unreachable!();
+            }
+        }
+    }
+
+    #[test]
+    fn check_op_deviation() {
+        let values = [2., 4., 4., 4., 5., 5., 7., 9.];
+        let mut variance = OpDeviation::new(OpType::Variance);
+        let mut stddev = OpDeviation::new(OpType::StdDev);
+        let mut cv = OpDeviation::new(OpType::Cv);
+        for x in values {
+            variance.update(&LogValue::Float(x));
+            stddev.update(&LogValue::Second(x));
+            cv.update(&LogValue::Float(x));
+        }
+        if let LogValue::Float(x) = variance.value() {
+            assert!((x - 32. / 7.).abs() < 1e-10);
+        } else {
+            unreachable!();
+        }
+        if let LogValue::Second(x) = stddev.value() {
+            assert!((x - (32. / 7_f64).sqrt()).abs() < 1e-10);
+        } else {
+            unreachable!();
+        }
+        if let LogValue::Float(x) = cv.value() {
+            assert!((x - (32. / 7_f64).sqrt() / 5.).abs() < 1e-10);
+        } else {
+            unreachable!();
+        }
+
+        // Welford stays accurate with a large offset.
+        let mut op = OpDeviation::new(OpType::Variance);
+        for x in values {
+            op.update(&LogValue::Float(x + 1e9));
+        }
+        if let LogValue::Float(x) = op.value() {
+            assert!((x - 32. / 7.).abs() < 1e-6);
+        } else {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn check_op_top_k() {
+        let mut op = OpTopK::new(2);
+        assert!(matches!(op.value(), LogValue::None));
+        for agent in ["curl", "firefox", "curl", "chrome", "firefox", "curl"] {
+            op.update(&LogValue::String(agent.to_string()));
+        }
+        if let LogValue::TopK(x) = op.value() {
+            assert_eq!(x, vec![(String::from("curl"), 3), (String::from("firefox"), 2)]);
+        } else {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn check_op_first_last() {
+        let mut first = OpFirstLast::new(false, None);
+        let mut last = OpFirstLast::new(true, None);
+        for v in [LogValue::None, LogValue::Integer(1), LogValue::Integer(2), LogValue::None] {
+            first.update(&v);
+            last.update(&v);
+        }
+        assert_eq!(first.value(), LogValue::Integer(1));
+        assert_eq!(last.value(), LogValue::Integer(2));
+
+        // Ordered by time property instead of input position.
+        let time = Accessor::from_string("time", "time", LogValueType::Timestamp);
+        let mut first = OpFirstLast::new(false, Some(time.clone()));
+        let mut last = OpFirstLast::new(true, Some(time));
+        let lines = [
+            r#"{"time": "2022-06-05T12:00:02Z"}"#,
+            r#"{"time": "2022-06-05T12:00:00Z"}"#,
+            r#"{"time": "2022-06-05T12:00:01Z"}"#,
+            r#"{}"#,
+        ];
+        for (i, line) in lines.iter().enumerate() {
+            let mut record = LogRecord::new("key");
+            record.raw = serde_json::from_str(line).unwrap();
+            record.position = i as u64;
+            let v = LogValue::Integer(i as u32);
+            first.update_with_record(&v, &record);
+            last.update_with_record(&v, &record);
+        }
+        assert_eq!(first.value(), LogValue::Integer(1));
+        assert_eq!(last.value(), LogValue::Integer(0));
+    }
+
+    #[test]
+    fn check_op_seen() {
+        let mut first_seen = OpSeen::new(false);
+        let mut last_seen = OpSeen::new(true);
+        for s in ["2022-06-05T12:00:01Z", "2022-06-05T12:00:00Z", "2022-06-05T21:00:02+09:00"] {
+            let t = DateTime::parse_from_rfc3339(s).unwrap();
+            first_seen.update(&LogValue::Timestamp(t));
+            last_seen.update(&LogValue::Timestamp(t));
+        }
+        first_seen.update(&LogValue::String(String::from("2000-01-01T00:00:00Z")));
+        assert_eq!(first_seen.value().as_string(), "2022-06-05T12:00:00Z");
+        assert_eq!(last_seen.value().as_string(), "2022-06-05T21:00:02+09:00");
+    }
+
+    #[test]
+    fn check_op_condition() {
+        let condition = Predicate::parse("status >= 500").unwrap();
+        let mut count = OpCondition::new(condition.clone(), Box::new(OpCount::new()));
+        let mut average = OpCondition::new(condition.clone(), Box::new(OpAverage::new()));
+        let mut ratio = OpRatio::new(condition);
+        for (status, latency) in [(200, 1.), (500, 2.), (503, 4.), (404, 8.)] {
+            let mut record = LogRecord::new("key");
+            record.raw = serde_json::json!({ "status": status });
+            let v = LogValue::Second(latency);
+            count.update_with_record(&v, &record);
+            average.update_with_record(&v, &record);
+            ratio.update_with_record(&v, &record);
+        }
+        assert_eq!(count.value(), LogValue::Integer(2));
+        assert_eq!(average.value(), LogValue::Float(3.));
+        assert_eq!(ratio.value(), LogValue::Float(0.5));
+    }
+
+    #[test]
+    fn check_op_histogram() {
+        let mut op = OpHistogram::new(Some(vec![0.1, 0.5, 1.]));
+        assert!(matches!(op.value(), LogValue::None));
+        for x in [0.05, 0.1, 0.2, 0.7, 3., 0.3] {
+            op.update(&LogValue::Second(x));
+        }
+        assert_eq!(op.value().as_string(), "<0.1:1 0.1-0.5:3 0.5-1:1 >=1:1");
+
+        let mut op = OpHistogram::new(None);
+        for x in [0., 0.3, 3., 3.5, 5.] {
+            op.update(&LogValue::Float(x));
+        }
+        assert_eq!(op.value().as_string(), "<0.25:1 0.25-0.5:1 0.5-1:0 1-2:0 2-4:2 4-8:1");
+
+        // Non-finite values are skipped in both modes.
+        for bounds in [None, Some(vec![1.])] {
+            let mut op = OpHistogram::new(bounds);
+            for x in [f64::INFINITY, f64::NEG_INFINITY, f64::NAN, 1.5] {
+                op.update(&LogValue::Float(x));
+            }
+            let total: u64 = match op.value() {
+                LogValue::Histogram(buckets) => buckets.iter().map(|b| b.count).sum(),
+                _ => 0,
+            };
+            assert_eq!(total, 1);
+        }
+    }
+
+    #[test]
+    fn check_op_apdex() {
+        let mut op = OpApdex::new(0.5);
+        assert!(matches!(op.value(), LogValue::None));
+        for x in [0.1, 0.5, 1.0, 2.0, 3.0, 0.2, 0.3, 0.4] {
+            op.update(&LogValue::Second(x));
+        }
+        op.update(&LogValue::None);
+        assert_eq!(op.value(), LogValue::Float((5. + 1.) / 8.));
+    }
+
+    #[test]
+    fn check_op_slo() {
+        let objective = Objective {
+            condition: Some(Predicate::parse("status < 500").unwrap()),
+            threshold: Some(1.),
+        };
+        let mut slo = OpSlo::new(objective.clone(), None);
+        let mut budget = OpSlo::new(objective, Some(0.9));
+        for (status, latency) in [(200, 0.5), (200, 0.8), (500, 0.1), (200, 1.5), (404, 0.2)] {
+            let mut record = LogRecord::new("key");
+            record.raw = serde_json::json!({ "status": status });
+            let v = LogValue::Second(latency);
+            slo.update_with_record(&v, &record);
+            budget.update_with_record(&v, &record);
+        }
+        assert_eq!(slo.value(), LogValue::Float(0.6));
+        // 40% of records are bad while 10% is allowed.
+        assert_eq!(budget.value(), LogValue::Float(1. - 0.4 / (1. - 0.9)));
+    }
+
+    #[test]
+    fn check_op_weighted() {
+        let record = LogRecord::new("key");
+        let mut count = OpCount::new();
+        let mut sum = OpSum::new();
+        let mut average = OpAverage::new();
+        for (x, w) in [(1, 10.), (4, 5.), (2, 1.)] {
+            let v = LogValue::Integer(x);
+            count.update_weighted(&v, w, &record);
+            sum.update_weighted(&v, w, &record);
+            average.update_weighted(&v, w, &record);
+        }
+        assert_eq!(count.value(), LogValue::Integer(16));
+        assert_eq!(sum.value(), LogValue::Integer(32));
+        assert_eq!(average.value(), LogValue::Float(2.));
+
+        count.update_weighted(&LogValue::Integer(1), 0.5, &record);
+        assert_eq!(count.value(), LogValue::Float(16.5));
+    }
+
+    #[test]
+    fn check_op_sum() {
+        let mut op = OpSum::new();
+        assert!(matches!(op.value(), LogValue::None));
+        op.update(&LogValue::Second(1.5));
+        op.update(&LogValue::Second(2.));
+        op.update(&LogValue::None);
+        assert_eq!(op.value(), LogValue::Second(3.5));
+    }
+
+    #[test]
+    fn check_op_merge() {
+        let condition = Predicate::parse("status >= 500").unwrap();
+        let op_types = [
+            OpType::Count, OpType::Sum, OpType::Average, OpType::Variance, OpType::Min, OpType::Max,
+            OpType::Percentile { rank: 50., exact: true },
+            OpType::Percentile { rank: 50., exact: false },
+            OpType::CountDistinct { exact: false, precision: 12 },
+            OpType::TopK { k: 2 }, OpType::First { time: None }, OpType::Last { time: None },
+            OpType::CountIf { condition: condition.clone() }, OpType::Ratio { condition },
+            OpType::Histogram { bounds: None }, OpType::Apdex { threshold: 4. },
+        ];
+        let values: Vec<(u32, u32)> = (1..10).map(|x| (x, if x % 3 == 0 { 500 } else { 200 })).collect();
+        for op_type in op_types.iter() {
+            // Merged result of split inputs equals to the result of the whole input.
+            let mut whole = build_operation(op_type);
+            let mut first = build_operation(op_type);
+            let mut second = build_operation(op_type);
+            for (i, (x, status)) in values.iter().enumerate() {
+                let mut record = LogRecord::new("key");
+                record.raw = serde_json::json!({ "status": status });
+                record.position = i as u64;
+                let v = LogValue::Integer(*x);
+                whole.update_with_record(&v, &record);
+                if i < 4 {
+                    first.update_with_record(&v, &record);
+                } else {
+                    second.update_with_record(&v, &record);
+                }
+            }
+            first.merge(second.as_ref());
+            assert_eq!(first.value(), whole.value());
+        }
+    }
+
+    #[test]
+    fn check_op_extreme() {
+        let mut min = OpExtreme::new(false);
+        let mut max = OpExtreme::new(true);
+        assert!(matches!(max.value(), LogValue::None));
+        for v in [LogValue::Second(0.5), LogValue::None, LogValue::Second(2.), LogValue::Second(0.1)] {
+            min.update(&v);
+            max.update(&v);
+        }
+        assert_eq!(min.value(), LogValue::Second(0.1));
+        assert_eq!(max.value(), LogValue::Second(2.));
+    }
+
     #[test]
     fn check_op_count() {
         let mut op = OpCount::new();
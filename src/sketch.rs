@@ -0,0 +1,412 @@
+use std::collections::{ BTreeMap, HashMap };
+use std::collections::hash_map::{ DefaultHasher };
+use std::hash::{ Hash, Hasher };
+
+/// Default relative accuracy of `QuantileSketch`.
+pub const DEFAULT_RELATIVE_ACCURACY: f64 = 0.01;
+/// Default upper bound of the number of bins kept by `QuantileSketch`.
+pub const DEFAULT_MAX_BINS: usize = 2048;
+
+/// Default precision of `HyperLogLog`.
+pub const DEFAULT_HLL_PRECISION: u8 = 14;
+
+/// Mergeable quantile sketch based on DDSketch.
+///
+/// Values are counted into logarithmically sized bins so that every quantile
+/// estimate is within `relative_accuracy` of the true value. When the number of
+/// bins exceeds `max_bins`, the bins closest to zero are collapsed, which keeps
+/// memory bounded regardless of the number of inputs.
+/// Estimates are interpolated between ranks like exact percentiles, and clamped
+/// to the observed minimum and maximum.
+#[derive(Clone)]
+pub struct QuantileSketch {
+    gamma: f64,
+    ln_gamma: f64,
+    max_bins: usize,
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+    zero_count: u64,
+    count: u64,
+    min: f64,
+    max: f64,
+}
+
+impl Default for QuantileSketch {
+    fn default() -> Self {
+        QuantileSketch::new(DEFAULT_RELATIVE_ACCURACY, DEFAULT_MAX_BINS)
+    }
+}
+
+impl QuantileSketch {
+    pub fn new(relative_accuracy: f64, max_bins: usize) -> Self {
+        let gamma = (1. + relative_accuracy) / (1. - relative_accuracy);
+        Self {
+            gamma,
+            ln_gamma: gamma.ln(),
+            max_bins,
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zero_count: 0,
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn add(&mut self, v: f64) {
+        if v.is_nan() {
+            return;
+        }
+        if v > f64::MIN_POSITIVE {
+            let i = self.index(v);
+            *self.positive.entry(i).or_insert(0) += 1;
+            Self::collapse(&mut self.positive, self.max_bins);
+        } else if v < -f64::MIN_POSITIVE {
+            let i = self.index(-v);
+            *self.negative.entry(i).or_insert(0) += 1;
+            Self::collapse(&mut self.negative, self.max_bins);
+        } else {
+            self.zero_count += 1;
+        }
+        self.count += 1;
+        self.min = self.min.min(v);
+        self.max = self.max.max(v);
+    }
+
+    /// Merge another sketch into this one. Both sketches must share the same accuracy.
+    pub fn merge(&mut self, other: &QuantileSketch) {
+        for (i, c) in other.positive.iter() {
+            *self.positive.entry(*i).or_insert(0) += c;
+        }
+        for (i, c) in other.negative.iter() {
+            *self.negative.entry(*i).or_insert(0) += c;
+        }
+        Self::collapse(&mut self.positive, self.max_bins);
+        Self::collapse(&mut self.negative, self.max_bins);
+        self.zero_count += other.zero_count;
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// Estimate the value at quantile `q` (0 <= q <= 1).
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 || !(0. ..=1.).contains(&q) {
+            return None;
+        }
+        // Interpolate between the values of neighboring ranks, like exact percentiles.
+        let pos = q * (self.count - 1) as f64;
+        let lower = self.value_at(pos.floor() as u64)?;
+        let upper = self.value_at(pos.ceil() as u64)?;
+        let estimate = lower + (upper - lower) * (pos - pos.floor());
+        Some(estimate.clamp(self.min, self.max))
+    }
+
+    /// Estimate the value of the `rank`-th smallest value, starting from 0.
+    fn value_at(&self, rank: u64) -> Option<f64> {
+        // Walk from the most negative value to the most positive value.
+        let mut seen = 0;
+        for (i, c) in self.negative.iter().rev() {
+            seen += c;
+            if seen > rank {
+                return Some(-self.value(*i));
+            }
+        }
+        seen += self.zero_count;
+        if seen > rank {
+            return Some(0.);
+        }
+        for (i, c) in self.positive.iter() {
+            seen += c;
+            if seen > rank {
+                return Some(self.value(*i));
+            }
+        }
+        None
+    }
+
+    fn index(&self, v: f64) -> i32 {
+        (v.ln() / self.ln_gamma).ceil() as i32
+    }
+
+    fn value(&self, i: i32) -> f64 {
+        2. * self.gamma.powi(i) / (self.gamma + 1.)
+    }
+
+    /// Fold the lowest bins together until at most `max_bins` bins are left.
+    fn collapse(bins: &mut BTreeMap<i32, u64>, max_bins: usize) {
+        while bins.len() > max_bins {
+            let (lowest, c) = match bins.iter().next() {
+                Some((i, c)) => (*i, *c),
+                None => return,
+            };
+            bins.remove(&lowest);
+            if let Some(next) = bins.values_mut().next() {
+                *next += c;
+            }
+        }
+    }
+}
+
+/// HyperLogLog cardinality estimator.
+///
+/// Uses `2^precision` one-byte registers, with a standard error of about
+/// `1.04 / sqrt(2^precision)`.
+#[derive(Clone)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog::new(DEFAULT_HLL_PRECISION)
+    }
+}
+
+impl HyperLogLog {
+    pub const MIN_PRECISION: u8 = 4;
+    pub const MAX_PRECISION: u8 = 18;
+
+    pub fn new(precision: u8) -> Self {
+        let precision = precision.clamp(Self::MIN_PRECISION, Self::MAX_PRECISION);
+        Self {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    pub fn add<T: Hash + ?Sized>(&mut self, v: &T) {
+        let mut hasher = DefaultHasher::new();
+        v.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let p = self.precision as u32;
+        let index = (hash >> (64 - p)) as usize;
+        // Position of the leftmost 1 bit in the remaining bits.
+        let rest = hash << p;
+        let rho = (rest.leading_zeros().min(64 - p) + 1) as u8;
+        if self.registers[index] < rho {
+            self.registers[index] = rho;
+        }
+    }
+
+    /// Merge another estimator into this one. Both must share the same precision.
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (r, o) in self.registers.iter_mut().zip(other.registers.iter()) {
+            if *r < *o {
+                *r = *o;
+            }
+        }
+    }
+
+    pub fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1. + 1.079 / m),
+        };
+        let mut sum = 0.;
+        let mut zeros = 0;
+        for r in self.registers.iter() {
+            sum += 2f64.powi(-(*r as i32));
+            if *r == 0 {
+                zeros += 1;
+            }
+        }
+        let raw = alpha * m * m / sum;
+        // Use linear counting for small cardinalities.
+        if raw <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        }
+    }
+}
+
+/// Space-Saving heavy hitters counter.
+///
+/// Tracks at most `capacity` values. When a new value arrives and the counter is full,
+/// the value with the smallest count is replaced and the new value inherits its count,
+/// so counts are over-estimated by at most the smallest count.
+#[derive(Clone)]
+pub struct SpaceSaving {
+    capacity: usize,
+    counters: HashMap<String, u64>,
+}
+
+impl SpaceSaving {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            counters: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, v: &str) {
+        if let Some(c) = self.counters.get_mut(v) {
+            *c += 1;
+            return;
+        }
+        if self.counters.len() < self.capacity {
+            self.counters.insert(v.to_string(), 1);
+            return;
+        }
+        if let Some((min_key, min_count)) = self.min_counter() {
+            self.counters.remove(&min_key);
+            self.counters.insert(v.to_string(), min_count + 1);
+        }
+    }
+
+    /// Merge another counter into this one, keeping the `capacity` largest counts.
+    pub fn merge(&mut self, other: &SpaceSaving) {
+        for (k, c) in other.counters.iter() {
+            *self.counters.entry(k.clone()).or_insert(0) += c;
+        }
+        while self.counters.len() > self.capacity {
+            if let Some((min_key, _)) = self.min_counter() {
+                self.counters.remove(&min_key);
+            }
+        }
+    }
+
+    /// The `k` most frequent values, ordered by count and then by value.
+    pub fn top(&self, k: usize) -> Vec<(String, u64)> {
+        let mut result: Vec<(String, u64)> = self.counters.iter()
+            .map(|(k, c)| (k.clone(), *c))
+            .collect();
+        result.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        result.truncate(k);
+        result
+    }
+
+    fn min_counter(&self) -> Option<(String, u64)> {
+        self.counters.iter()
+            .min_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+            .map(|(k, c)| (k.clone(), *c))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_relative_eq(actual: f64, expected: f64, accuracy: f64) {
+        assert!((actual - expected).abs() <= expected.abs() * accuracy,
+            "actual: {}, expected: {}", actual, expected);
+    }
+
+    #[test]
+    fn check_quantile_sketch() {
+        let mut sketch = QuantileSketch::default();
+        assert!(sketch.quantile(0.5).is_none());
+        for n in 1..1001 {
+            sketch.add(n as f64);
+        }
+        assert_eq!(sketch.count(), 1000);
+        assert_relative_eq(sketch.quantile(0.5).unwrap(), 500., 0.01);
+        assert_relative_eq(sketch.quantile(0.99).unwrap(), 990., 0.01);
+        assert_relative_eq(sketch.quantile(1.).unwrap(), 1000., 0.01);
+    }
+
+    #[test]
+    fn check_quantile_sketch_small() {
+        let mut sketch = QuantileSketch::default();
+        sketch.add(0.1);
+        sketch.add(1.5);
+        // Interpolated like exact percentiles, within the observed range.
+        assert_relative_eq(sketch.quantile(0.99).unwrap(), 1.486, 0.01);
+        assert!(sketch.quantile(0.99).unwrap() <= 1.5);
+        assert_eq!(sketch.quantile(0.).unwrap(), 0.1);
+        assert_eq!(sketch.quantile(1.).unwrap(), 1.5);
+    }
+
+    #[test]
+    fn check_quantile_sketch_merge() {
+        let mut a = QuantileSketch::default();
+        let mut b = QuantileSketch::default();
+        for n in 1..501 {
+            a.add(n as f64);
+        }
+        for n in 501..1001 {
+            b.add(n as f64);
+        }
+        a.merge(&b);
+        assert_eq!(a.count(), 1000);
+        assert_relative_eq(a.quantile(0.9).unwrap(), 900., 0.01);
+    }
+
+    #[test]
+    fn check_hyperloglog() {
+        let mut hll = HyperLogLog::default();
+        assert_eq!(hll.estimate(), 0.);
+        for n in 0..100000 {
+            hll.add(&format!("user{}", n % 20000));
+        }
+        assert_relative_eq(hll.estimate(), 20000., 0.03);
+
+        let mut small = HyperLogLog::new(10);
+        for n in 0..10 {
+            small.add(&n);
+        }
+        assert_eq!(small.estimate().round(), 10.);
+    }
+
+    #[test]
+    fn check_hyperloglog_merge() {
+        let mut a = HyperLogLog::new(12);
+        let mut b = HyperLogLog::new(12);
+        for n in 0..5000 {
+            a.add(&n);
+            b.add(&(n + 2500));
+        }
+        a.merge(&b);
+        assert_relative_eq(a.estimate(), 7500., 0.05);
+    }
+
+    #[test]
+    fn check_space_saving() {
+        let mut counter = SpaceSaving::new(10);
+        for n in 0..10000 {
+            // "a" and "b" are heavy hitters among many rare values.
+            if n % 3 == 0 {
+                counter.add("a");
+            } else if n % 5 == 0 {
+                counter.add("b");
+            } else {
+                counter.add(&format!("rare{}", n));
+            }
+        }
+        assert!(counter.counters.len() <= 10);
+        let top = counter.top(2);
+        assert_eq!(top[0].0, "a");
+        assert_eq!(top[1].0, "b");
+        assert!(top[0].1 >= 3334);
+
+        let mut other = SpaceSaving::new(10);
+        for _ in 0..5000 {
+            other.add("b");
+        }
+        counter.merge(&other);
+        assert!(counter.counters.len() <= 10);
+        assert_eq!(counter.top(1)[0].0, "b");
+    }
+
+    #[test]
+    fn check_quantile_sketch_bounded() {
+        let mut sketch = QuantileSketch::new(0.01, 64);
+        for n in 0..10000 {
+            sketch.add(1.1_f64.powi(n % 500) - 5.);
+        }
+        assert!(sketch.positive.len() <= 64);
+        assert!(sketch.negative.len() <= 64);
+        assert_eq!(sketch.count(), 10000);
+    }
+}
@@ -0,0 +1,301 @@
+use crate::log_record::{ LogValue, LogValueType, parse_value };
+
+use chrono::{ DateTime, FixedOffset, Offset, SecondsFormat, TimeZone };
+use chrono_tz::{ Tz };
+use std::cmp::{ Ordering };
+use std::error::Error;
+use std::io;
+
+/// Maximum width of buckets in seconds, about 100 years.
+const MAX_WIDTH: i64 = 36500 * 24 * 60 * 60;
+
+/// Fixed width time interval to group timestamps, aligned to a timezone.
+#[derive(Clone, Debug)]
+pub struct TimeBucket {
+    /// Width of the bucket in seconds.
+    pub width: i64,
+    pub timezone: Zone,
+}
+
+/// Timezone to align buckets and labels to.
+#[derive(Clone, Debug)]
+pub enum Zone {
+    Fixed(FixedOffset),
+    /// IANA timezone such as `Asia/Tokyo`, whose offset may change with daylight saving time.
+    Named(Tz),
+}
+
+impl Zone {
+    /// Offset from UTC in seconds at the timestamp.
+    fn offset_at(&self, timestamp: i64) -> i64 {
+        match self {
+            Zone::Fixed(offset) => offset.local_minus_utc() as i64,
+            Zone::Named(tz) => match tz.timestamp_opt(timestamp, 0).single() {
+                Some(t) => t.offset().fix().local_minus_utc() as i64,
+                None => 0,
+            },
+        }
+    }
+
+    /// Timestamp of the local time given in seconds, the earlier one if it occurs twice.
+    /// `None` if the local time is skipped.
+    fn timestamp_of_local(&self, local: i64) -> Option<i64> {
+        match self {
+            Zone::Fixed(offset) => Some(local - offset.local_minus_utc() as i64),
+            Zone::Named(tz) => {
+                let naive = DateTime::from_timestamp(local, 0)?.naive_utc();
+                tz.from_local_datetime(&naive).earliest().map(|t| t.timestamp())
+            },
+        }
+    }
+
+    fn at(&self, timestamp: i64) -> Option<DateTime<FixedOffset>> {
+        match self {
+            Zone::Fixed(offset) => offset.timestamp_opt(timestamp, 0).single(),
+            Zone::Named(tz) => tz.timestamp_opt(timestamp, 0).single().map(|t| t.fixed_offset()),
+        }
+    }
+}
+
+impl TimeBucket {
+    /// Parse width like `30s`, `5m`, `1h` or `1d`, and timezone like `UTC`, `+09:00` or `Asia/Tokyo`.
+    pub fn parse(width: &str, timezone: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let invalid = || -> Box<dyn Error> {
+            Box::new(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid bucket value: {}", width)))
+        };
+        let width = width.trim();
+        let unit = match width.chars().last() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 60 * 60,
+            Some('d') => 24 * 60 * 60,
+            _ => { return Err(invalid()); }
+        };
+        let num = width[..width.len() - 1].parse::<i64>().map_err(|_| invalid())?;
+        if num <= 0 {
+            return Err(invalid());
+        }
+        let timezone = match timezone {
+            Some(tz) => parse_timezone(tz)?,
+            None => Zone::Fixed(utc()),
+        };
+        // Wider buckets would overflow timestamps when stepping to the next bucket.
+        let width = num.checked_mul(unit).filter(|w| *w <= MAX_WIDTH).ok_or_else(invalid)?;
+        Ok(Self { width, timezone })
+    }
+
+    /// Start of the bucket containing `t`, in the timezone of the bucket.
+    pub fn floor(&self, t: &DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+        self.at(self.floor_timestamp(t.timestamp()))
+    }
+
+    /// Start of the bucket following the bucket starting at `start`.
+    pub fn next(&self, start: &DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+        // A bucket is longer than its width when clocks go back within it, so step until the next one.
+        let mut t = start.timestamp() + self.width;
+        let mut next = self.floor_timestamp(t);
+        while next <= start.timestamp() {
+            t += 60 * 60;
+            next = self.floor_timestamp(t);
+        }
+        self.at(next)
+    }
+
+    fn floor_timestamp(&self, timestamp: i64) -> i64 {
+        let offset = self.timezone.offset_at(timestamp);
+        let local = timestamp + offset;
+        let local_start = local - local.rem_euclid(self.width);
+        let start = local_start - offset;
+        // The offset at the start differs when daylight saving time begins or ends within the bucket.
+        if self.timezone.offset_at(start) == offset {
+            start
+        } else {
+            self.timezone.timestamp_of_local(local_start).unwrap_or(start)
+        }
+    }
+
+    /// Label of the bucket containing the timestamp string, or `None` if it is not a timestamp.
+    pub fn key(&self, s: &str) -> Option<String> {
+        match parse_value(&LogValueType::Timestamp, s) {
+            LogValue::Timestamp(t) => Some(label(&self.floor(&t))),
+            _ => None,
+        }
+    }
+
+    fn at(&self, timestamp: i64) -> DateTime<FixedOffset> {
+        match self.timezone.at(timestamp) {
+            Some(t) => t,
+            None => utc().timestamp_opt(0, 0).unwrap(),
+        }
+    }
+}
+
+/// Range of buckets to fill with empty rows.
+/// Bounds default to the first and the last observed buckets.
+#[derive(Clone, Debug, Default)]
+pub struct GapFill {
+    pub from: Option<DateTime<FixedOffset>>,
+    pub to: Option<DateTime<FixedOffset>>,
+}
+
+impl GapFill {
+    pub fn parse(from: Option<&str>, to: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let parse = |s: Option<&str>| -> Result<Option<DateTime<FixedOffset>>, Box<dyn Error>> {
+            match s {
+                Some(s) => match parse_value(&LogValueType::Timestamp, s) {
+                    LogValue::Timestamp(t) => Ok(Some(t)),
+                    _ => Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid timestamp: {}", s)))),
+                },
+                None => Ok(None),
+            }
+        };
+        Ok(Self { from: parse(from)?, to: parse(to)? })
+    }
+
+    /// Number of buckets between `from` and `to`, if both are given.
+    pub fn bucket_count(&self, bucket: &TimeBucket) -> Option<i64> {
+        let (from, to) = (self.from?, self.to?);
+        let span = bucket.floor(&to).timestamp() - bucket.floor(&from).timestamp();
+        Some((span / bucket.width).max(-1) + 1)
+    }
+
+    /// Labels of all buckets in the range. `observed` are labels of observed buckets.
+    /// Returns at most `limit` labels.
+    pub fn labels<'a>(&self, bucket: &TimeBucket, observed: impl Iterator<Item = &'a String>, limit: usize) -> Vec<String> {
+        let mut first = self.from.map(|t| bucket.floor(&t));
+        let mut last = self.to.map(|t| bucket.floor(&t));
+        for label in observed {
+            if let Ok(t) = DateTime::parse_from_rfc3339(label) {
+                if self.from.is_none() && first.is_none_or(|f| t < f) {
+                    first = Some(t);
+                }
+                if self.to.is_none() && last.is_none_or(|l| t > l) {
+                    last = Some(t);
+                }
+            }
+        }
+
+        let mut result: Vec<String> = vec![];
+        if let (Some(first), Some(last)) = (first, last) {
+            let mut t = first;
+            while t <= last && result.len() < limit {
+                result.push(label(&t));
+                t = bucket.next(&t);
+            }
+        }
+        result
+    }
+}
+
+/// Label of the bucket, with the offset at the start of the bucket.
+/// Labels do not sort chronologically when the offset changes, so compare them with `compare_labels`.
+pub fn label(start: &DateTime<FixedOffset>) -> String {
+    start.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Compare labels by the start time of buckets. `None` if either is not a label.
+pub fn compare_labels(a: &str, b: &str) -> Option<Ordering> {
+    let a = DateTime::parse_from_rfc3339(a).ok()?;
+    let b = DateTime::parse_from_rfc3339(b).ok()?;
+    Some(a.cmp(&b))
+}
+
+fn utc() -> FixedOffset {
+    FixedOffset::east_opt(0).unwrap()
+}
+
+/// Parse a fixed offset like `+09:00`, or an IANA timezone name like `Asia/Tokyo`.
+fn parse_timezone(s: &str) -> Result<Zone, Box<dyn Error>> {
+    let invalid = || -> Box<dyn Error> {
+        Box::new(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid timezone value: {}", s)))
+    };
+    if s == "UTC" || s == "Z" {
+        return Ok(Zone::Fixed(utc()));
+    }
+    let sign = match s.chars().next() {
+        Some('+') => 1,
+        Some('-') => -1,
+        _ => { return s.parse::<Tz>().map(Zone::Named).map_err(|_| invalid()); }
+    };
+    let (h, m) = s[1..].split_once(':').unwrap_or((&s[1..], "0"));
+    let h = h.parse::<i32>().map_err(|_| invalid())?;
+    let m = m.parse::<i32>().map_err(|_| invalid())?;
+    FixedOffset::east_opt(sign * (h * 3600 + m * 60)).map(Zone::Fixed).ok_or_else(invalid)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_time_bucket() {
+        let bucket = TimeBucket::parse("5m", None).unwrap();
+        assert_eq!(bucket.width, 300);
+        assert_eq!(bucket.key("2022-06-05T12:34:56.789Z").unwrap(), "2022-06-05T12:30:00Z");
+        assert_eq!(bucket.key("2022-06-05T21:34:56+09:00").unwrap(), "2022-06-05T12:30:00Z");
+        assert!(bucket.key("not a time").is_none());
+
+        // Days are aligned to the timezone.
+        let bucket = TimeBucket::parse("1d", Some("+09:00")).unwrap();
+        assert_eq!(bucket.key("2022-06-05T16:00:00Z").unwrap(), "2022-06-06T00:00:00+09:00");
+        assert_eq!(bucket.key("2022-06-05T14:59:59Z").unwrap(), "2022-06-05T00:00:00+09:00");
+
+        let start = bucket.floor(&DateTime::parse_from_rfc3339("2022-06-05T14:59:59Z").unwrap());
+        assert_eq!(label(&bucket.next(&start)), "2022-06-06T00:00:00+09:00");
+    }
+
+    #[test]
+    fn check_time_bucket_dst() {
+        // Clocks go forward at 2022-03-13T02:00 and back at 2022-11-06T02:00 in New York.
+        let bucket = TimeBucket::parse("1d", Some("America/New_York")).unwrap();
+        assert_eq!(bucket.key("2022-01-10T04:59:59Z").unwrap(), "2022-01-09T00:00:00-05:00");
+        assert_eq!(bucket.key("2022-06-05T04:00:00Z").unwrap(), "2022-06-05T00:00:00-04:00");
+        assert_eq!(bucket.key("2022-03-13T12:00:00Z").unwrap(), "2022-03-13T00:00:00-05:00");
+        assert_eq!(bucket.key("2022-11-06T12:00:00Z").unwrap(), "2022-11-06T00:00:00-04:00");
+
+        // Days of 23 and 25 hours are followed by the next day.
+        let start = bucket.floor(&DateTime::parse_from_rfc3339("2022-03-13T12:00:00Z").unwrap());
+        assert_eq!(label(&bucket.next(&start)), "2022-03-14T00:00:00-04:00");
+        let start = bucket.floor(&DateTime::parse_from_rfc3339("2022-11-06T12:00:00Z").unwrap());
+        assert_eq!(label(&bucket.next(&start)), "2022-11-07T00:00:00-05:00");
+
+        // The repeated hour is split into two buckets.
+        let bucket = TimeBucket::parse("1h", Some("America/New_York")).unwrap();
+        assert_eq!(bucket.key("2022-11-06T05:30:00Z").unwrap(), "2022-11-06T01:00:00-04:00");
+        assert_eq!(bucket.key("2022-11-06T06:30:00Z").unwrap(), "2022-11-06T01:00:00-05:00");
+        let start = bucket.floor(&DateTime::parse_from_rfc3339("2022-11-06T05:30:00Z").unwrap());
+        assert_eq!(label(&bucket.next(&start)), "2022-11-06T01:00:00-05:00");
+    }
+
+    #[test]
+    fn check_gap_fill() {
+        let bucket = TimeBucket::parse("1m", None).unwrap();
+        let observed = [String::from("2022-06-05T12:03:00Z"), String::from("2022-06-05T12:01:00Z")];
+        let labels = GapFill::default().labels(&bucket, observed.iter(), 100);
+        assert_eq!(labels, vec!["2022-06-05T12:01:00Z", "2022-06-05T12:02:00Z", "2022-06-05T12:03:00Z"]);
+
+        let fill = GapFill::parse(Some("2022-06-05T12:00:30Z"), None).unwrap();
+        assert_eq!(fill.labels(&bucket, observed.iter(), 100).len(), 4);
+        assert_eq!(fill.labels(&bucket, observed.iter(), 2).len(), 2);
+        assert!(GapFill::default().labels(&bucket, [].iter(), 100).is_empty());
+        assert!(GapFill::parse(None, Some("tomorrow")).is_err());
+
+        let fill = GapFill::parse(Some("2022-06-05T12:00:30Z"), Some("2022-06-05T13:00:00Z")).unwrap();
+        assert_eq!(fill.bucket_count(&bucket), Some(61));
+        assert_eq!(GapFill::default().bucket_count(&bucket), None);
+    }
+
+    #[test]
+    fn check_time_bucket_error() {
+        assert!(TimeBucket::parse("5", None).is_err());
+        assert!(TimeBucket::parse("0m", None).is_err());
+        assert!(TimeBucket::parse("1w", None).is_err());
+        assert!(TimeBucket::parse("99999999999999d", None).is_err());
+        assert!(TimeBucket::parse("999999999999999999d", None).is_err());
+        assert!(TimeBucket::parse("36500d", None).is_ok());
+        assert!(TimeBucket::parse("1h", Some("Asia/Tokyo")).is_ok());
+        assert!(TimeBucket::parse("1h", Some("Mars/Olympus")).is_err());
+        assert!(TimeBucket::parse("1h", Some("-05:30")).is_ok());
+    }
+}
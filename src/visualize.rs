@@ -1,65 +1,73 @@
 
 
 use std::cmp;
-pub use crate::aggregate::{ Table, TableRow, TableDef, Index, Field };
-pub use crate::log_record::{ LogValueType, LogValue, LogRecord, Accessor };
+use std::io::{ self, Write };
+use crate::aggregate::{ RowKind, Table };
+use crate::log_record::{ LogValue };
+use serde_json::{ Map, Value };
 
 
 pub enum VisualizeType {
     Csv,
-    Markdown
+    Markdown,
+    Json
 }
 
-pub fn display_as_csv(table: &mut Table) {
+pub fn display_as_csv(table: &mut Table, out: &mut dyn Write) -> io::Result<()> {
     // # Display aggregated result as csv format.
-    let def = table.definition.clone();
-    let mut header = String::from("");
-    for  (i, &f) in def.field_accessor().iter().enumerate() {
-        header += &f.name;
-        if i != def.field_num() {
-            header += ",";
+    let header: Vec<String> = header(table).iter().map(|s| escape_csv(s)).collect();
+    writeln!(out, "{}", header.join(","))?;
+
+    for (key, values) in rows(table) {
+        let mut cells: Vec<String> = key.iter()
+            .map(|k| escape_csv(k.as_deref().unwrap_or("")))
+            .collect();
+        for v in values.iter() {
+            cells.push(escape_csv(&v.as_string()));
         }
+        writeln!(out, "{}", cells.join(","))?;
     }
-    println!("{}", &header);
-
-    for (key, row) in table.sorted_rows() {
-        let mut row_str = String::from(key);
-        row_str += ",";
+    Ok(())
+}
 
-        for (i, v) in row.get_row(&def.fields).iter().enumerate() {
 
-            let s  = v.as_string();
-            row_str += &s;
-            if i != def.field_num() {
-                row_str += ",";
+pub fn display_as_markdown(table :&mut Table, out: &mut dyn Write) -> io::Result<()> {
+    // # Display aggregated result as markdown format.
+    let def = table.definition.clone();
+    let header = header(table);
+    let key_num = def.index.len();
+    let mut rows: Vec<(Vec<String>, Vec<String>)> = vec![];
+    for (kind, key, values) in table.output_rows() {
+        let mut cells: Vec<String> = def.key_cells(kind, &key).into_iter()
+            .map(|k| k.unwrap_or_default())
+            .collect();
+        // With rollup, only the deepest index is shown to indent rows as a tree.
+        if def.rollup && (kind == RowKind::Group || kind == RowKind::Subtotal) {
+            for cell in cells.iter_mut().take(key.len() - 1) {
+                cell.clear();
             }
-
         }
-        println!("{}", &row_str);
+        let values = values.iter().map(|v| v.as_string()).collect();
+        rows.push((cells, values));
     }
-}
 
-
-pub fn display_as_markdown(table :&mut Table) {
-    // # Display aggregated result as csv format.
     // count max chars of each fields;
-    let def = table.definition.clone();
-    let mut col_width = vec![10; 1+def.fields.len()];
-    col_width[0] = cmp::max(col_width[0], def.index.name().chars().count());
-    for (i, &f) in def.field_accessor().iter().enumerate() {
-        col_width[i+1] = cmp::max(col_width[i+1], f.name.chars().count());
+    let mut col_width: Vec<usize> = header.iter()
+        .map(|h| cmp::max(10, h.chars().count()))
+        .collect();
+    for (key, values) in rows.iter() {
+        for (i, s) in key.iter().chain(values.iter()).enumerate() {
+            col_width[i] = cmp::max(col_width[i], s.chars().count());
+        }
     }
     
     // print header;
-    let mut header = String::from("|");
-    header += &format_string(def.index.name(), col_width[0]);
-    header += "|";
-    for (i, &f) in def.field_accessor().iter().enumerate() {
-        let width = col_width[i+1];
-        header += &format_string(&f.name, width);
-        header += "|"
+    let mut header_str = String::from("|");
+    for (h, w) in header.iter().zip(col_width.iter()) {
+        header_str += &format_string(h, *w);
+        header_str += "|";
     }
-    println!("{}", &header);
+    writeln!(out, "{}", &header_str)?;
 
     // print separator
     let mut separator = String::new();
@@ -67,33 +75,107 @@ pub fn display_as_markdown(table :&mut Table) {
         separator += &format!("|:{}", "-".repeat(*w-1));
     }
     separator += "|";
-    println!("{}", separator);
+    writeln!(out, "{}", separator)?;
 
     // print table
-    for (key, row) in table.sorted_rows() {
-        let width = col_width[0];
+    for (key, values) in rows.iter() {
         let mut row_str = String::from("|");
-        row_str += &format_string(key, width);
-        row_str += "|";
-
-        for (i, v) in row.get_row(&def.fields).iter().enumerate() {
-            let width = col_width[i+1];
-            row_str += &format_log_value(v, width);
-            if i != def.field_num() {
-                row_str += "|";
-            }
+        for (i, k) in key.iter().enumerate() {
+            row_str += &format_string(k, col_width[i]);
+            row_str += "|";
+        }
+        for (i, v) in values.iter().enumerate() {
+            row_str += &format_value(v, col_width[key_num + i]);
+            row_str += "|";
+        }
+        writeln!(out, "{}", &row_str)?;
+    }
+    Ok(())
+}
 
+pub fn display_as_json(table: &mut Table, out: &mut dyn Write) -> io::Result<()> {
+    // # Display aggregated result as json array of row objects.
+    // Each row has its kind in `_row`, and indexes of total, others and undefined rows are null,
+    // so that they can not be confused with index values of the same name.
+    let def = table.definition.clone();
+    let names = table.value_names();
+    let mut result: Vec<Value> = vec![];
+    for (kind, key, values) in table.output_rows() {
+        let mut obj = Map::new();
+        obj.insert(String::from("_row"), Value::String(kind.name().to_string()));
+        for (i, name) in def.index_names().iter().enumerate() {
+            let k = match kind {
+                RowKind::Group | RowKind::Subtotal => key.get(i).cloned(),
+                _ => None,
+            };
+            obj.insert(name.to_string(), k.map_or(Value::Null, Value::String));
+        }
+        for (name, v) in names.iter().zip(values.iter()) {
+            obj.insert(name.clone(), v.to_json());
         }
-        println!("{}", &row_str);
+        result.push(Value::Object(obj));
     }
+    writeln!(out, "{}", Value::Array(result))
+}
+
+/// Column names. Index names followed by value column names.
+fn header(table: &Table) -> Vec<String> {
+    let mut result: Vec<String> = table.definition.index_names().iter().map(|s| s.to_string()).collect();
+    result.extend(table.value_names());
+    result
+}
+
+/// Sorted rows with the cells of indexes and the values of each field.
+fn rows(table: &mut Table) -> Vec<(Vec<Option<String>>, Vec<LogValue>)> {
+    let def = table.definition.clone();
+    table.output_rows().into_iter()
+        .map(|(kind, key, values)| (def.key_cells(kind, &key), values))
+        .collect()
 }
 
+/// Quote a csv cell if it contains a separator, a quote or a newline.
+fn escape_csv(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
 
-fn format_log_value(v: &LogValue, width: usize) -> String{
-    let s  = v.as_string();
-    format!("{:>width$}", &s, width = width)
+fn format_value(s: &str, width: usize) -> String{
+    format!("{:>width$}", s, width = width)
 }
 
 fn format_string(s: &str, width: usize) -> String{
     format!("{:width$}", s, width = width)
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregate::{ Field, Index, TableDef };
+    use crate::log_record::{ Accessor, LogValueType };
+    use crate::operation::{ OpType };
+    use std::io::{ Cursor };
+
+    #[test]
+    fn check_json_row_kind() {
+        let index = vec![Index::new(Accessor::from_string("service", "service", LogValueType::String))];
+        let count = Field::new(Accessor::from_string("count", "service", LogValueType::String), OpType::Count);
+        let mut def = TableDef::new(index, vec![count], None, true);
+        def.total = true;
+        let mut table = Table::new(def);
+        let lines = [r#"{"service": "total"}"#, r#"{"status": 200}"#];
+        table.aggregate(Box::new(Cursor::new(lines.join("\n"))));
+
+        let mut out: Vec<u8> = vec![];
+        display_as_json(&mut table, &mut out).unwrap();
+        let rows: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(rows, serde_json::json!([
+            {"_row": "group", "service": "total", "count": 1},
+            {"_row": "undefined", "service": null, "count": 0},
+            {"_row": "total", "service": null, "count": 1},
+        ]));
+    }
+}